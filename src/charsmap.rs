@@ -0,0 +1,225 @@
+//! `NormalizerSpec.precompiled_charsmap` of SentencePiece.
+//!
+//! The blob consists of
+//! 1. the byte size of the trie as a little endian u32,
+//! 2. a darts-clone double array, mapping UTF-8 keys to offsets in 3.,
+//! 3. null-terminated normalized strings.
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CharsMap {
+    trie: Vec<u32>,
+    normalized: Vec<u8>,
+}
+
+impl CharsMap {
+    pub fn from_blob(blob: &[u8]) -> Result<Self> {
+        if blob.len() <= 4 {
+            return Err(anyhow!("precompiled_charsmap is too short"));
+        }
+        let trie_size = u32::from_le_bytes(blob[..4].try_into().unwrap()) as usize;
+        let blob = &blob[4..];
+        if trie_size >= blob.len() || !trie_size.is_multiple_of(4) {
+            return Err(anyhow!("invalid trie size: {}", trie_size));
+        }
+        let trie = blob[..trie_size]
+            .chunks(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok(Self {
+            trie,
+            normalized: blob[trie_size..].to_vec(),
+        })
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(4 + self.trie.len() * 4 + self.normalized.len());
+        ret.extend_from_slice(&((self.trie.len() * 4) as u32).to_le_bytes());
+        for unit in &self.trie {
+            ret.extend_from_slice(&unit.to_le_bytes());
+        }
+        ret.extend_from_slice(&self.normalized);
+        ret
+    }
+
+    /// Compiles `source -> target` rules into a double array.
+    pub fn build(rules: &BTreeMap<String, String>) -> Self {
+        let mut normalized = vec![];
+        let mut keys = vec![];
+        for (src, tgt) in rules {
+            debug_assert!(!src.is_empty() && !src.contains('\0'));
+            keys.push((src.as_bytes(), normalized.len() as u32));
+            normalized.extend_from_slice(tgt.as_bytes());
+            normalized.push(0);
+        }
+        let mut builder = DoubleArrayBuilder::default();
+        builder.build(&keys);
+        Self {
+            trie: builder.units,
+            normalized,
+        }
+    }
+
+    /// Finds the longest rule matching the prefix of `s`.
+    ///
+    /// Returns the byte length of the matched prefix and its replacement.
+    pub fn normalize_prefix<'a>(&'a self, s: &str) -> Option<(usize, &'a str)> {
+        let mut ret = None;
+        let trie = &self.trie;
+        let mut pos = offset(*trie.first()?) as usize;
+        for (i, &b) in s.as_bytes().iter().enumerate() {
+            pos ^= b as usize;
+            let unit = match trie.get(pos) {
+                Some(&unit) if label(unit) == b as u32 => unit,
+                _ => break,
+            };
+            pos ^= offset(unit) as usize;
+            if has_leaf(unit) {
+                match trie.get(pos) {
+                    Some(&unit) => ret = Some((i + 1, value(unit) as usize)),
+                    None => break,
+                }
+            }
+        }
+        let (len, value) = ret?;
+        // A match in the middle of a UTF-8 char is never produced by `build`
+        if !s.is_char_boundary(len) {
+            return None;
+        }
+        let normalized = self.normalized.get(value..)?;
+        let end = normalized.iter().position(|&b| b == 0)?;
+        Some((len, std::str::from_utf8(&normalized[..end]).ok()?))
+    }
+}
+
+fn has_leaf(unit: u32) -> bool {
+    (unit >> 8) & 1 == 1
+}
+
+fn value(unit: u32) -> u32 {
+    unit & ((1 << 31) - 1)
+}
+
+fn label(unit: u32) -> u32 {
+    unit & ((1 << 31) | 0xff)
+}
+
+fn offset(unit: u32) -> u32 {
+    (unit >> 10) << ((unit & (1 << 9)) >> 6)
+}
+
+/// Minimal builder of darts-clone double arrays.
+///
+/// The layout differs from the one of darts-clone itself, but any reader of darts-clone can
+/// traverse it.
+#[derive(Default)]
+struct DoubleArrayBuilder {
+    units: Vec<u32>,
+    /// unused ids less than `units.len()`
+    free: BTreeSet<usize>,
+    used_offsets: HashSet<usize>,
+}
+
+impl DoubleArrayBuilder {
+    /// `keys` must be sorted and unique
+    fn build(&mut self, keys: &[(&[u8], u32)]) {
+        self.reserve(0);
+        if !keys.is_empty() {
+            self.build_node(keys, 0, 0);
+        }
+    }
+
+    fn reserve(&mut self, id: usize) {
+        if id >= self.units.len() {
+            self.free.extend(self.units.len()..id + 1);
+            self.units.resize(id + 1, 0);
+        }
+        self.free.remove(&id);
+    }
+
+    fn is_free(&self, id: usize) -> bool {
+        id >= self.units.len() || self.free.contains(&id)
+    }
+
+    fn find_offset(&self, labels: &[u8]) -> usize {
+        let is_valid = |offset: usize| {
+            offset != 0
+                && !self.used_offsets.contains(&offset)
+                && labels.iter().all(|&l| self.is_free(offset ^ l as usize))
+        };
+        self.free
+            .iter()
+            .copied()
+            .chain(self.units.len()..)
+            .map(|id| id ^ labels[0] as usize)
+            .find(|&offset| is_valid(offset))
+            .unwrap()
+    }
+
+    fn build_node(&mut self, keys: &[(&[u8], u32)], depth: usize, id: usize) {
+        // a key ending at `depth` comes first, since keys are sorted
+        let mut value = None;
+        let mut children: Vec<(u8, usize)> = vec![];
+        for (i, (key, v)) in keys.iter().enumerate() {
+            match key.get(depth) {
+                None => value = Some(*v),
+                Some(&l) => {
+                    if children.last().map(|c| c.0) != Some(l) {
+                        children.push((l, i));
+                    }
+                }
+            }
+        }
+        let labels: Vec<_> = value
+            .map(|_| 0)
+            .into_iter()
+            .chain(children.iter().map(|c| c.0))
+            .collect();
+        let offset = self.find_offset(&labels);
+        assert!(offset < 1 << 21, "charsmap is too large");
+        self.used_offsets.insert(offset);
+        set_offset(&mut self.units[id], (id ^ offset) as u32);
+        for &l in &labels {
+            self.reserve(offset ^ l as usize);
+        }
+        if let Some(v) = value {
+            self.units[id] |= 1 << 8;
+            self.units[offset] = v | (1 << 31);
+        }
+        for (i, &(l, begin)) in children.iter().enumerate() {
+            let end = children.get(i + 1).map(|x| x.1).unwrap_or_else(|| keys.len());
+            let child = offset ^ l as usize;
+            self.units[child] = (self.units[child] & !0xff) | l as u32;
+            self.build_node(&keys[begin..end], depth + 1, child);
+        }
+    }
+}
+
+fn set_offset(unit: &mut u32, offset: u32) {
+    *unit &= (1 << 31) | (1 << 8) | 0xff;
+    *unit |= offset << 10;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_and_match() {
+        let rules: BTreeMap<_, _> = [("a", "A"), ("ab", "X"), ("abc", ""), ("ｂ", "b"), ("b", "bb")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let map = CharsMap::build(&rules);
+        let map = CharsMap::from_blob(&map.to_blob()).unwrap();
+        assert_eq!(map.normalize_prefix("a"), Some((1, "A")));
+        assert_eq!(map.normalize_prefix("abd"), Some((2, "X")));
+        assert_eq!(map.normalize_prefix("abcd"), Some((3, "")));
+        assert_eq!(map.normalize_prefix("ｂa"), Some((3, "b")));
+        assert_eq!(map.normalize_prefix("ba"), Some((1, "bb")));
+        assert_eq!(map.normalize_prefix("c"), None);
+        assert_eq!(map.normalize_prefix(""), None);
+    }
+}
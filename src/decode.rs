@@ -1,22 +1,172 @@
-use crate::protos::sentencepiece_model::ModelProto;
-use anyhow::Result;
-use clap::Clap;
-use log;
-use std::fs::File;
-
-#[derive(Clap)]
-pub struct DecodeOpts {
-    #[clap(short, long)]
-    model_path: String,
+use crate::norm;
+use crate::protos::sentencepiece_model::{ModelProto, ModelProto_SentencePiece_Type};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Detokenizes pieces or ids into text.
+///
+/// Control pieces are dropped, `<unk>` is rendered with `TrainerSpec.unk_surface` and
+/// `norm::SPACE_REP` is replaced with a space. Byte pieces and pieces of byte-level models are
+/// mapped back to bytes, and invalid UTF-8 is replaced with U+FFFD.
+pub struct Decoder {
+    pieces: Vec<(String, ModelProto_SentencePiece_Type)>,
+    types: HashMap<String, ModelProto_SentencePiece_Type>,
+    unk_surface: String,
+    byte_level: bool,
+    add_dummy_prefix: bool,
+    treat_whitespace_as_suffix: bool,
 }
 
-pub fn decode(spec: DecodeOpts) -> Result<()> {
-    let mut model = File::open(&spec.model_path)?;
-    let model: ModelProto = protobuf::parse_from_reader(&mut model)?;
-    log::info!("Loaded model");
-    println!("{:?}", model); // DEBUG
-    for p in model.get_pieces() {
-        eprintln!("{:?}", p); // DEBUG
+impl Decoder {
+    pub fn new(model: &ModelProto) -> Self {
+        let pieces: Vec<_> = model
+            .get_pieces()
+            .iter()
+            .map(|p| (p.get_piece().to_string(), p.get_field_type()))
+            .collect();
+        let types = pieces.iter().cloned().collect();
+        Self {
+            pieces,
+            types,
+            unk_surface: model.get_trainer_spec().get_unk_surface().to_string(),
+            byte_level: model.get_normalizer_spec().get_byte_level(),
+            add_dummy_prefix: model.get_normalizer_spec().get_add_dummy_prefix(),
+            treat_whitespace_as_suffix: model.get_trainer_spec().get_treat_whitespace_as_suffix(),
+        }
+    }
+
+    pub fn decode_ids(&self, ids: &[usize]) -> Result<String> {
+        let pieces = ids
+            .iter()
+            .map(|&i| match self.pieces.get(i) {
+                Some((p, t)) => Ok((p.as_str(), *t)),
+                None => Err(anyhow!("id {} is out of range", i)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.detokenize(pieces))
+    }
+
+    /// Pieces not in the vocabulary are regarded as the surface of unknown chars.
+    pub fn decode_pieces<'a>(&self, pieces: impl IntoIterator<Item = &'a str>) -> String {
+        self.detokenize(pieces.into_iter().map(|p| {
+            let t = self
+                .types
+                .get(p)
+                .copied()
+                .unwrap_or(ModelProto_SentencePiece_Type::NORMAL);
+            (p, t)
+        }))
+    }
+
+    fn detokenize<'a>(
+        &self,
+        pieces: impl IntoIterator<Item = (&'a str, ModelProto_SentencePiece_Type)>,
+    ) -> String {
+        let mut bytes = vec![];
+        for (p, t) in pieces {
+            match t {
+                ModelProto_SentencePiece_Type::CONTROL | ModelProto_SentencePiece_Type::UNUSED => {}
+                ModelProto_SentencePiece_Type::UNKNOWN => {
+                    bytes.extend_from_slice(self.unk_surface.as_bytes())
+                }
+                ModelProto_SentencePiece_Type::USER_DEFINED => {
+                    bytes.extend_from_slice(p.as_bytes())
+                }
+                ModelProto_SentencePiece_Type::BYTE => match norm::parse_byte_piece(p) {
+                    Some(b) => bytes.push(b),
+                    None => bytes.extend_from_slice(p.as_bytes()),
+                },
+                _ => {
+                    for c in p.chars() {
+                        match norm::char_to_byte(c).filter(|_| self.byte_level) {
+                            Some(b) => bytes.push(b),
+                            None if c == norm::SPACE_REP => bytes.push(b' '),
+                            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        }
+                    }
+                }
+            }
+        }
+        let mut ret = String::from_utf8_lossy(&bytes).into_owned();
+        // remove the space added by `norm::to_chars`
+        if self.add_dummy_prefix {
+            if self.treat_whitespace_as_suffix {
+                if ret.ends_with(' ') {
+                    ret.pop();
+                }
+            } else if ret.starts_with(' ') {
+                ret.remove(0);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::sentencepiece_model::ModelProto_SentencePiece;
+
+    #[test]
+    fn test_decode() {
+        let mut model = ModelProto::new();
+        for (s, t) in &[
+            ("<unk>", ModelProto_SentencePiece_Type::UNKNOWN),
+            ("<s>", ModelProto_SentencePiece_Type::CONTROL),
+            ("</s>", ModelProto_SentencePiece_Type::CONTROL),
+            ("▁ab", ModelProto_SentencePiece_Type::NORMAL),
+            ("c", ModelProto_SentencePiece_Type::NORMAL),
+            ("▁", ModelProto_SentencePiece_Type::NORMAL),
+        ] {
+            let mut p = ModelProto_SentencePiece::new();
+            p.set_piece(s.to_string());
+            p.set_field_type(*t);
+            model.mut_pieces().push(p);
+        }
+        let decoder = Decoder::new(&model);
+        assert_eq!(decoder.decode_ids(&[1, 3, 4, 5, 0, 2]).unwrap(), "abc  \u{2047} ");
+        assert_eq!(
+            decoder.decode_pieces(vec!["<s>", "▁ab", "x", "▁", "<unk>"]),
+            "abx  \u{2047} "
+        );
+        assert!(decoder.decode_ids(&[6]).is_err());
+    }
+
+    #[test]
+    fn test_decode_byte_level() {
+        let mut model = ModelProto::new();
+        for s in &["<unk>", "\u{120}\u{e3}\u{123}", "\u{123}", "\u{e3}", "x"] {
+            let mut p = ModelProto_SentencePiece::new();
+            p.set_piece(s.to_string());
+            model.mut_pieces().push(p);
+        }
+        model.mut_pieces()[0].set_field_type(ModelProto_SentencePiece_Type::UNKNOWN);
+        model.mut_normalizer_spec().set_byte_level(true);
+        let decoder = Decoder::new(&model);
+        // "\u{3041}" is E3 81 81, and "\u{3042}" is E3 81 82
+        assert_eq!(decoder.decode_ids(&[1, 2, 4]).unwrap(), "\u{3041}x");
+        assert_eq!(
+            decoder.decode_pieces(vec!["\u{120}\u{e3}\u{123}", "\u{124}", "\u{120}\u{120}"]),
+            "\u{3042}  "
+        );
+        assert_eq!(decoder.decode_ids(&[3, 0]).unwrap(), "\u{fffd} \u{2047} ");
+    }
+
+    #[test]
+    fn test_decode_dummy_prefix() {
+        let mut model = ModelProto::new();
+        for s in &["<unk>", "▁a", "b▁", "▁"] {
+            let mut p = ModelProto_SentencePiece::new();
+            p.set_piece(s.to_string());
+            model.mut_pieces().push(p);
+        }
+        let decode = |model: &ModelProto| Decoder::new(model).decode_ids(&[1, 2, 3]).unwrap();
+        assert_eq!(decode(&model), "ab  ");
+        model
+            .mut_trainer_spec()
+            .set_treat_whitespace_as_suffix(true);
+        assert_eq!(decode(&model), " ab ");
+        model.mut_normalizer_spec().set_add_dummy_prefix(false);
+        assert_eq!(decode(&model), " ab  ");
     }
-    Ok(())
 }
@@ -0,0 +1,503 @@
+use crate::norm;
+use crate::protos::sentencepiece_model::{ModelProto, ModelProto_SentencePiece_Type};
+use crate::split::Boundaries;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
+
+/// Applies the merges of a BPE model.
+///
+/// Without explicit merges, symbols are merged in the same way as upstream SentencePiece: a pair
+/// of adjacent symbols can be merged if their concatenation is a normal piece in the model, in
+/// descending order of score, which is the reverse order of the merges learned by the trainer.
+/// With merges stored in the model, only the recorded `(left, right)` pairs are merged, in the
+/// order of their ranks. Ties are broken by the leftmost position.
+///
+/// Unused pieces are merged as well, but split back into the pair they were merged from. With
+/// `TrainerSpec.byte_fallback`, unknown chars are encoded as the byte pieces of their UTF-8.
+/// User defined pieces are matched greedily before the merges. Pieces spanning the boundaries of
+/// `TrainerSpec`, e.g. Unicode scripts or the pre-tokens of GPT-2, are never made, as in
+/// training.
+pub struct Encoder {
+    pieces: HashMap<String, usize>,
+    scores: Vec<f32>,
+    unused: HashSet<usize>,
+    unk_id: usize,
+    /// ids of `<0x00>`..`<0xFF>`, or empty without byte fallback
+    byte_ids: Vec<usize>,
+    /// user defined `(piece, id)`
+    symbols: Vec<(String, usize)>,
+    /// chars of `symbols` to be matched
+    symbol_chars: Vec<Vec<char>>,
+    /// `(left, right)` -> rank
+    merges: HashMap<(String, String), usize>,
+    boundaries: Boundaries,
+    /// The char which whitespace is replaced with, ignored by `boundaries`
+    space: char,
+}
+
+/// merged piece -> pair of pieces, for unused pieces
+type RevMerge = HashMap<String, (String, String)>;
+
+/// `(piece, id, range of chars)`
+type Spans = Vec<(String, usize, Range<usize>)>;
+
+#[derive(Debug)]
+struct Symbol {
+    start: usize,
+    end: usize,
+    prev: usize,
+    next: usize,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    score: f32,
+    left: usize,
+    right: usize,
+    len: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // `BinaryHeap` is a max-heap: higher score first, then leftmost
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+impl Encoder {
+    /// `merges` may be empty, to merge by scores.
+    pub fn new(model: &ModelProto, merges: &[(String, String)]) -> Self {
+        let mut pieces = HashMap::new();
+        let mut scores = vec![];
+        let mut unused = HashSet::new();
+        let mut unk_id = 0;
+        let mut byte_ids = HashMap::new();
+        let mut symbols = vec![];
+        for (i, p) in model.get_pieces().iter().enumerate() {
+            match p.get_field_type() {
+                ModelProto_SentencePiece_Type::NORMAL => {
+                    pieces.insert(p.get_piece().to_string(), i);
+                }
+                ModelProto_SentencePiece_Type::UNUSED => {
+                    pieces.insert(p.get_piece().to_string(), i);
+                    unused.insert(i);
+                }
+                ModelProto_SentencePiece_Type::UNKNOWN => unk_id = i,
+                ModelProto_SentencePiece_Type::USER_DEFINED => {
+                    symbols.push((p.get_piece().to_string(), i));
+                }
+                ModelProto_SentencePiece_Type::BYTE => {
+                    if let Some(b) = norm::parse_byte_piece(p.get_piece()) {
+                        byte_ids.insert(b, i);
+                    }
+                }
+                _ => {}
+            }
+            scores.push(p.get_score());
+        }
+        let byte_ids = if model.get_trainer_spec().get_byte_fallback() {
+            let ids: Option<Vec<_>> = (0..=255).map(|b| byte_ids.get(&b).copied()).collect();
+            if ids.is_none() {
+                log::warn!("byte_fallback is disabled, since some byte pieces are missing");
+            }
+            ids.unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let byte_level = model.get_normalizer_spec().get_byte_level();
+        let symbol_chars = symbols
+            .iter()
+            .map(|(s, _)| norm::symbol_chars(s, byte_level))
+            .collect();
+        let merges = merges
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.clone(), i))
+            .collect();
+        Self {
+            pieces,
+            scores,
+            unused,
+            unk_id,
+            byte_ids,
+            symbols,
+            symbol_chars,
+            merges,
+            boundaries: Boundaries::from_spec(model.get_trainer_spec(), byte_level),
+            space: norm::space_char(
+                byte_level,
+                model.get_normalizer_spec().get_escape_whitespaces(),
+            ),
+        }
+    }
+
+    /// Encodes normalized chars into `(piece, id)` pairs.
+    ///
+    /// Unknown chars are returned as their surface with the id of `<unk>`, or as byte pieces with
+    /// byte fallback.
+    pub fn encode(&self, chars: &[char]) -> Vec<(String, usize)> {
+        self.encode_with_spans(chars)
+            .into_iter()
+            .map(|(piece, id, _)| (piece, id))
+            .collect()
+    }
+
+    /// Same as `encode`, with the range of `chars` each piece is made from. The byte pieces of a
+    /// char are the first with the range of the char, and the rest with the empty range after it.
+    pub fn encode_with_spans(&self, chars: &[char]) -> Vec<(String, usize, Range<usize>)> {
+        let mut ret = vec![];
+        let mut start = 0;
+        for (segment, symbol) in norm::split_symbols(chars, &self.symbol_chars) {
+            match symbol {
+                Some(i) => {
+                    let (piece, id) = self.symbols[i].clone();
+                    ret.push((piece, id, start..start + segment.len()));
+                }
+                None => {
+                    for r in self.boundaries.pre_tokens(segment) {
+                        self.encode_merges(&segment[r.clone()], start + r.start, &mut ret);
+                    }
+                }
+            }
+            start += segment.len();
+        }
+        ret
+    }
+
+    /// Encodes `chars` starting at `offset` of the input.
+    fn encode_merges(&self, chars: &[char], offset: usize, ret: &mut Spans) {
+        let mut symbols: Vec<_> = (0..chars.len())
+            .map(|i| Symbol {
+                start: i,
+                end: i + 1,
+                prev: i.wrapping_sub(1),
+                next: i + 1,
+            })
+            .collect();
+        let mut agenda = BinaryHeap::new();
+        let mut rev_merge = RevMerge::new();
+        for i in 1..symbols.len() {
+            self.push_candidate(chars, &symbols, i - 1, i, &mut agenda, &mut rev_merge);
+        }
+
+        while let Some(Candidate {
+            left, right, len, ..
+        }) = agenda.pop()
+        {
+            // skip if either symbol has already been merged
+            let (l, r) = (&symbols[left], &symbols[right]);
+            if l.start == l.end || r.start == r.end || l.end - l.start + r.end - r.start != len {
+                continue;
+            }
+            symbols[left].end = symbols[right].end;
+            symbols[left].next = symbols[right].next;
+            symbols[right].end = symbols[right].start;
+            let next = symbols[left].next;
+            if next < symbols.len() {
+                symbols[next].prev = left;
+                self.push_candidate(chars, &symbols, left, next, &mut agenda, &mut rev_merge);
+            }
+            let prev = symbols[left].prev;
+            if prev < symbols.len() {
+                self.push_candidate(chars, &symbols, prev, left, &mut agenda, &mut rev_merge);
+            }
+        }
+
+        let mut i = 0;
+        while i < symbols.len() {
+            let s = &symbols[i];
+            let piece: String = chars[s.start..s.end].iter().collect();
+            self.resegment(piece, offset + s.start, &rev_merge, ret);
+            i = s.next;
+        }
+    }
+
+    /// Pushes `piece` starting at `start` of the input, split into known pieces.
+    fn resegment(&self, piece: String, start: usize, rev_merge: &RevMerge, ret: &mut Spans) {
+        let id = match self.pieces.get(&piece) {
+            Some(&id) => id,
+            None if !self.byte_ids.is_empty() => {
+                let mut buf = [0; 4];
+                for (i, c) in piece.chars().enumerate() {
+                    let pos = start + i;
+                    for (j, b) in c.encode_utf8(&mut buf).bytes().enumerate() {
+                        let span = if j == 0 {
+                            pos..pos + 1
+                        } else {
+                            pos + 1..pos + 1
+                        };
+                        ret.push((norm::byte_piece(b), self.byte_ids[b as usize], span));
+                    }
+                }
+                return;
+            }
+            None => self.unk_id,
+        };
+        if self.unused.contains(&id) {
+            if let Some((left, right)) = rev_merge.get(&piece) {
+                let mid = start + left.chars().count();
+                self.resegment(left.clone(), start, rev_merge, ret);
+                self.resegment(right.clone(), mid, rev_merge, ret);
+                return;
+            }
+        }
+        let end = start + piece.chars().count();
+        ret.push((piece, id, start..end));
+    }
+
+    fn push_candidate(
+        &self,
+        chars: &[char],
+        symbols: &[Symbol],
+        left: usize,
+        right: usize,
+        agenda: &mut BinaryHeap<Candidate>,
+        rev_merge: &mut RevMerge,
+    ) {
+        let (l, r) = (&symbols[left], &symbols[right]);
+        if !self.boundaries.allows(&chars[l.start..r.end], self.space) {
+            return;
+        }
+        let piece: String = chars[l.start..r.end].iter().collect();
+        let id = match self.pieces.get(&piece) {
+            Some(&id) => id,
+            None => return,
+        };
+        let pair: (String, String) = (
+            chars[l.start..l.end].iter().collect(),
+            chars[r.start..r.end].iter().collect(),
+        );
+        let score = if self.merges.is_empty() {
+            self.scores[id]
+        } else {
+            match self.merges.get(&pair) {
+                Some(&rank) => -(rank as f32),
+                None => return,
+            }
+        };
+        if self.unused.contains(&id) {
+            rev_merge.insert(piece.clone(), pair);
+        }
+        agenda.push(Candidate {
+            score,
+            left,
+            right,
+            len: r.end - l.start,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::sentencepiece_model::ModelProto_SentencePiece;
+
+    fn model(pieces: &[(&str, f32, ModelProto_SentencePiece_Type)]) -> ModelProto {
+        let mut model = ModelProto::new();
+        let mut unk = ModelProto_SentencePiece::new();
+        unk.set_piece("<unk>".into());
+        unk.set_field_type(ModelProto_SentencePiece_Type::UNKNOWN);
+        model.mut_pieces().push(unk);
+        for &(s, score, t) in pieces {
+            let mut p = ModelProto_SentencePiece::new();
+            p.set_piece(s.to_string());
+            p.set_score(score);
+            p.set_field_type(t);
+            model.mut_pieces().push(p);
+        }
+        model
+    }
+
+    #[test]
+    fn test_encode() {
+        use ModelProto_SentencePiece_Type::NORMAL;
+        let model = model(&[
+            ("bc", 0., NORMAL),
+            ("abc", -1., NORMAL),
+            ("ab", -2., NORMAL),
+            ("a", -3., NORMAL),
+            ("b", -4., NORMAL),
+            ("c", -5., NORMAL),
+        ]);
+        let encoder = Encoder::new(&model, &[]);
+        let chars: Vec<_> = "abcabxab".chars().collect();
+        assert_eq!(
+            encoder.encode(&chars),
+            vec![
+                ("abc".to_string(), 2),
+                ("ab".to_string(), 3),
+                ("x".to_string(), 0),
+                ("ab".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_unused() {
+        use ModelProto_SentencePiece_Type::{NORMAL, UNUSED};
+        let model = model(&[
+            ("ab", 0., NORMAL),
+            ("abc", -1., UNUSED),
+            ("a", -2., NORMAL),
+            ("b", -3., NORMAL),
+            ("c", -4., NORMAL),
+        ]);
+        let encoder = Encoder::new(&model, &[]);
+        let chars: Vec<_> = "abcc".chars().collect();
+        assert_eq!(
+            encoder.encode(&chars),
+            vec![
+                ("ab".to_string(), 1),
+                ("c".to_string(), 5),
+                ("c".to_string(), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_merges() {
+        use ModelProto_SentencePiece_Type::NORMAL;
+        let model = model(&[
+            ("bc", 0., NORMAL),
+            ("ab", -1., NORMAL),
+            ("abc", -2., NORMAL),
+            ("a", -3., NORMAL),
+            ("b", -4., NORMAL),
+            ("c", -5., NORMAL),
+        ]);
+        let merges: Vec<_> = [("b", "c"), ("a", "b"), ("ab", "c")]
+            .iter()
+            .map(|&(l, r)| (l.to_string(), r.to_string()))
+            .collect();
+        let chars: Vec<_> = "abc".chars().collect();
+        // "a" + "bc" is a piece, but not a merge
+        assert_eq!(
+            Encoder::new(&model, &merges).encode(&chars),
+            vec![("a".to_string(), 4), ("bc".to_string(), 1)]
+        );
+        assert_eq!(
+            Encoder::new(&model, &[]).encode(&chars),
+            vec![("abc".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_encode_byte_fallback() {
+        use ModelProto_SentencePiece_Type::{BYTE, NORMAL};
+        let bytes: Vec<_> = (0..=255).map(norm::byte_piece).collect();
+        let mut pieces: Vec<_> = bytes.iter().map(|s| (s.as_str(), 0., BYTE)).collect();
+        pieces.extend(&[("ab", -1., NORMAL), ("a", -2., NORMAL), ("b", -3., NORMAL)]);
+        let mut model = model(&pieces);
+        let chars: Vec<_> = "abé".chars().collect();
+        assert_eq!(
+            Encoder::new(&model, &[]).encode(&chars),
+            vec![("ab".to_string(), 257), ("é".to_string(), 0)]
+        );
+        model.mut_trainer_spec().set_byte_fallback(true);
+        assert_eq!(
+            Encoder::new(&model, &[]).encode(&chars),
+            vec![
+                ("ab".to_string(), 257),
+                ("<0xC3>".to_string(), 0xc3 + 1),
+                ("<0xA9>".to_string(), 0xa9 + 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_user_defined() {
+        use ModelProto_SentencePiece_Type::{NORMAL, USER_DEFINED};
+        let model = model(&[
+            ("<m>", 0., USER_DEFINED),
+            ("<mask>", 0., USER_DEFINED),
+            ("ab", -1., NORMAL),
+            ("a", -2., NORMAL),
+            ("b", -3., NORMAL),
+            ("<", -4., NORMAL),
+        ]);
+        let chars: Vec<_> = "a<mask>b<m><ab".chars().collect();
+        assert_eq!(
+            Encoder::new(&model, &[]).encode(&chars),
+            vec![
+                ("a".to_string(), 4),
+                ("<mask>".to_string(), 2),
+                ("b".to_string(), 5),
+                ("<m>".to_string(), 1),
+                ("<".to_string(), 6),
+                ("ab".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_boundaries() {
+        use ModelProto_SentencePiece_Type::NORMAL;
+        let mut model = model(&[
+            ("▁a1", 0., NORMAL),
+            ("▁a", -1., NORMAL),
+            ("▁", -2., NORMAL),
+            ("a", -3., NORMAL),
+            ("1", -4., NORMAL),
+        ]);
+        let chars: Vec<_> = "▁a1".chars().collect();
+        assert_eq!(
+            Encoder::new(&model, &[]).encode(&chars),
+            vec![("▁a".to_string(), 2), ("1".to_string(), 5)]
+        );
+        model.mut_trainer_spec().set_split_by_number(false);
+        assert_eq!(
+            Encoder::new(&model, &[]).encode(&chars),
+            vec![("▁a1".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_encode_with_spans() {
+        use ModelProto_SentencePiece_Type::{BYTE, NORMAL, UNUSED, USER_DEFINED};
+        let mut pieces = vec![
+            ("<m>", 0., USER_DEFINED),
+            ("ab", 0., UNUSED),
+            ("a", -1., NORMAL),
+            ("b", -2., NORMAL),
+        ];
+        let bytes: Vec<_> = (0..=255).map(norm::byte_piece).collect();
+        pieces.extend(bytes.iter().map(|b| (b.as_str(), 0., BYTE)));
+        let mut model = model(&pieces);
+        model.mut_trainer_spec().set_byte_fallback(true);
+        let chars: Vec<_> = "ab<m>éa".chars().collect();
+        let spans: Vec<_> = Encoder::new(&model, &[])
+            .encode_with_spans(&chars)
+            .into_iter()
+            .map(|(piece, _, span)| (piece, span))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("a".to_string(), 0..1),
+                ("b".to_string(), 1..2),
+                ("<m>".to_string(), 2..5),
+                ("<0xC3>".to_string(), 5..6),
+                ("<0xA9>".to_string(), 6..6),
+                ("a".to_string(), 6..7),
+            ]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Import and export of GPT-2 style models, i.e. `vocab.json` and `merges.txt`.
+use crate::norm::byte_to_char;
+use crate::protos::sentencepiece_model::{
+    ModelProto, ModelProto_SentencePiece, ModelProto_SentencePiece_Type, NormalizerSpec,
+    TrainerSpec_ModelType,
+};
+use crate::return_err;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::prelude::*;
+
+/// First line of `merges.txt` written by GPT-2 and Hugging Face `tokenizers`
+const MERGES_HEADER: &str = "#version: 0.2";
+
+/// Builds a model from the contents of `vocab.json` (`{"piece": id, ...}`) and `merges.txt`
+/// (`left right` per line).
+///
+/// Pieces keep their ids. Pieces made by merges get scores in the order of merges, and the other
+/// pieces follow, as in upstream SentencePiece BPE models. Pieces of more than one char which no
+/// merge makes are special tokens: `TrainerSpec.unk_piece` is `UNKNOWN`, and the others are
+/// `USER_DEFINED`, which are matched in text as the added tokens of `tokenizers`. No
+/// normalization is applied, text is mapped to bytes as in byte-level models, and split by the
+/// pre-tokenization pattern of GPT-2 instead of the other boundaries.
+///
+/// Fails if there is no `<unk>` and some bytes are not in `vocab`, since such bytes could not be
+/// encoded.
+pub fn parse(vocab: &str, merges: &str) -> Result<ModelProto> {
+    let vocab: HashMap<String, usize> = serde_json::from_str(vocab)?;
+    let mut pieces = vec![None; vocab.len()];
+    for (piece, &id) in &vocab {
+        match pieces.get_mut(id) {
+            Some(p @ None) => *p = Some(piece.as_str()),
+            Some(Some(other)) => {
+                return_err!("id {} of {:?} is also of {:?}", id, piece, other);
+            }
+            None => {
+                return_err!("id {} of {:?} is out of range", id, piece);
+            }
+        }
+    }
+    // every id is filled, since ids are unique and in range
+    let pieces: Vec<_> = pieces.into_iter().map(Option::unwrap).collect();
+
+    let merges = parse_merges(merges)?;
+    let mut merged = HashMap::new();
+    for (left, right) in &merges {
+        for s in &[left, right] {
+            if !vocab.contains_key(s.as_str()) {
+                return_err!("{:?} in merges is not in vocab", s);
+            }
+        }
+        let piece = format!("{}{}", left, right);
+        if !vocab.contains_key(&piece) {
+            return_err!(
+                "{:?} merged from {:?} is not in vocab",
+                piece,
+                (left, right)
+            );
+        }
+        let rank = merged.len();
+        merged.entry(piece).or_insert(rank);
+    }
+
+    let mut model = ModelProto::new();
+    let spec = model.mut_trainer_spec();
+    spec.set_model_type(TrainerSpec_ModelType::BPE);
+    spec.set_vocab_size(pieces.len() as i32);
+    let id_of = |s: &str| vocab.get(s).map_or(-1, |&id| id as i32);
+    let unk_id = id_of(spec.get_unk_piece());
+    let bos_id = id_of(spec.get_bos_piece());
+    let eos_id = id_of(spec.get_eos_piece());
+    let pad_id = id_of(spec.get_pad_piece());
+    spec.set_unk_id(unk_id);
+    spec.set_bos_id(bos_id);
+    spec.set_eos_id(eos_id);
+    spec.set_pad_id(pad_id);
+    spec.set_split_by_unicode_script(false);
+    spec.set_split_by_number(false);
+    spec.set_split_by_whitespace(false);
+    spec.set_split_by_gpt2_pattern(true);
+    if unk_id < 0 {
+        let missing = (0..=255)
+            .map(byte_to_char)
+            .find(|c| !vocab.contains_key(&c.to_string()));
+        if let Some(c) = missing {
+            return_err!("no <unk>, and the byte {:?} is not in vocab", c);
+        }
+    }
+
+    let n_merged = merged.len();
+    for (i, &s) in pieces.iter().enumerate() {
+        let mut p = ModelProto_SentencePiece::new();
+        p.set_piece(s.to_string());
+        if let Some(&rank) = merged.get(s) {
+            p.set_score(-(rank as f32));
+        } else if s.chars().count() == 1 {
+            p.set_score(-((n_merged + i) as f32));
+        } else if i as i32 == unk_id {
+            p.set_field_type(ModelProto_SentencePiece_Type::UNKNOWN);
+        } else {
+            p.set_field_type(ModelProto_SentencePiece_Type::USER_DEFINED);
+        }
+        model.mut_pieces().push(p);
+    }
+    model.set_merges(&merges);
+
+    let mut spec = NormalizerSpec::new();
+    spec.set_name(crate::norm::IDENTITY.to_string());
+    spec.set_add_dummy_prefix(false);
+    spec.set_remove_extra_whitespaces(false);
+    spec.set_byte_level(true);
+    model.set_normalizer_spec(spec);
+    Ok(model)
+}
+
+/// Lines of `left right`, with an optional `#version` header.
+fn parse_merges(merges: &str) -> Result<Vec<(String, String)>> {
+    let mut ret = vec![];
+    let mut seen = HashSet::new();
+    for (i, line) in merges.lines().enumerate() {
+        if line.is_empty() || (i == 0 && line.starts_with("#version")) {
+            continue;
+        }
+        let mut it = line.split(' ');
+        let merge = match (it.next(), it.next(), it.next()) {
+            (Some(left), Some(right), None) if !left.is_empty() && !right.is_empty() => {
+                (left.to_string(), right.to_string())
+            }
+            _ => {
+                return_err!("invalid merge at line {}: {:?}", i + 1, line);
+            }
+        };
+        if !seen.insert(merge.clone()) {
+            return_err!("duplicated merge at line {}: {:?}", i + 1, line);
+        }
+        ret.push(merge);
+    }
+    Ok(ret)
+}
+
+/// Writes `vocab.json` with pieces in the order of ids.
+pub fn write_vocab<W: Write>(model: &ModelProto, mut w: W) -> Result<()> {
+    write!(w, "{{")?;
+    for (i, p) in model.get_pieces().iter().enumerate() {
+        if i > 0 {
+            write!(w, ",")?;
+        }
+        write!(w, "{}:{}", serde_json::to_string(p.get_piece())?, i)?;
+    }
+    writeln!(w, "}}")?;
+    Ok(())
+}
+
+/// Writes `merges.txt` in the order of merges.
+pub fn write_merges<W: Write>(model: &ModelProto, mut w: W) -> Result<()> {
+    writeln!(w, "{}", MERGES_HEADER)?;
+    for (left, right) in model.get_merges()? {
+        if left.contains(' ') || right.contains(' ') {
+            return_err!("merge {:?} has a space", (&left, &right));
+        }
+        writeln!(w, "{} {}", left, right)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    #[test]
+    fn test_parse() {
+        let vocab = r#"{"<unk>": 0, "a": 1, "b": 2, "ab": 3, "abb": 4, "<|endoftext|>": 5}"#;
+        let model = parse(vocab, "#version: 0.2\na b\nab b\n").unwrap();
+        let pieces: Vec<_> = model
+            .get_pieces()
+            .iter()
+            .map(|p| (p.get_piece(), p.get_field_type(), p.get_score()))
+            .collect();
+        use ModelProto_SentencePiece_Type::*;
+        assert_eq!(
+            pieces,
+            vec![
+                ("<unk>", UNKNOWN, 0.),
+                ("a", NORMAL, -3.),
+                ("b", NORMAL, -4.),
+                ("ab", NORMAL, 0.),
+                ("abb", NORMAL, -1.),
+                ("<|endoftext|>", USER_DEFINED, 0.),
+            ]
+        );
+        let spec = model.get_trainer_spec();
+        assert_eq!(
+            (spec.get_unk_id(), spec.get_bos_id(), spec.get_eos_id()),
+            (0, -1, -1)
+        );
+        assert!(spec.get_split_by_gpt2_pattern());
+        assert!(!spec.get_split_by_number());
+
+        // pieces never span the pre-tokens of GPT-2
+        let vocab = r#"{"<unk>": 0, "a": 1, "1": 2, "a1": 3}"#;
+        let model = Model::from_proto(parse(vocab, "a 1\n").unwrap()).unwrap();
+        assert_eq!(model.encode_as_pieces("a1"), vec!["a", "1"]);
+
+        // every byte must be in vocab without `<unk>`
+        let bytes: HashMap<_, _> = (0..=255)
+            .map(|b| (byte_to_char(b).to_string(), b))
+            .collect();
+        let vocab = serde_json::to_string(&bytes).unwrap();
+        assert!(parse(&vocab, "").is_ok());
+        assert!(parse(r#"{"a": 0, "b": 1, "ab": 2}"#, "a b\n").is_err());
+
+        assert!(parse(r#"{"<unk>": 0, "a": 1, "b": 3}"#, "").is_err());
+        assert!(parse(r#"{"<unk>": 0, "a": 1, "b": 1}"#, "").is_err());
+        assert!(parse(r#"{"<unk>": 0, "a": 1, "b": 2}"#, "a b\n").is_err());
+        assert!(parse(r#"{"<unk>": 0, "a": 1, "b": 2, "ab": 3}"#, "a b c\n").is_err());
+        assert!(parse(r#"{"<unk>": 0, "a": 1, "b": 2, "ab": 3}"#, "a b\na b\n").is_err());
+        assert!(parse(r#"{"<unk>": 0, "a": 1, "b": 2, "ab": 3}"#, "a b\n").is_ok());
+    }
+}
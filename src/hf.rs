@@ -0,0 +1,289 @@
+//! Conversion of models into the `tokenizer.json` of Hugging Face `tokenizers`.
+use crate::charsmap::CharsMap;
+use crate::model::Model;
+use crate::norm::{case_folding_rules, Rule};
+use crate::protos::sentencepiece_model::{ModelProto, ModelProto_SentencePiece_Type};
+use crate::return_err;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Builds a `tokenizer.json` with a BPE model, and the normalizer and the Metaspace
+/// pre-tokenizer and decoder equivalent to `Normalizer::to_chars`. The replacement of Metaspace is
+/// U+0020 without `escape_whitespaces`. Byte-level models get the ByteLevel pre-tokenizer and
+/// decoder instead, and models with byte fallback get the ByteFallback decoder before the
+/// Metaspace one.
+///
+/// Fails with `treat_whitespace_as_suffix`, since `tokenizers` can only put whitespace at the
+/// beginning of pieces.
+///
+/// Unknown, control and user defined pieces are exported as added tokens. Models without
+/// explicit merges, e.g. the ones trained by upstream SentencePiece, get merges in the order of
+/// scores.
+pub fn to_tokenizer_json(model: &Model) -> Result<Value> {
+    if model.normalizer().treat_whitespace_as_suffix {
+        return_err!("treat_whitespace_as_suffix is not supported by tokenizers");
+    }
+    let proto = model.proto();
+    let mut added_tokens = vec![];
+    let mut unk_token = Value::Null;
+    for (i, p) in proto.get_pieces().iter().enumerate() {
+        let special = match p.get_field_type() {
+            ModelProto_SentencePiece_Type::UNKNOWN => {
+                unk_token = json!(p.get_piece());
+                true
+            }
+            ModelProto_SentencePiece_Type::CONTROL => true,
+            ModelProto_SentencePiece_Type::USER_DEFINED => false,
+            _ => continue,
+        };
+        added_tokens.push(json!({
+            "id": i,
+            "content": p.get_piece(),
+            "single_word": false,
+            "lstrip": false,
+            "rstrip": false,
+            "normalized": !special,
+            "special": special,
+        }));
+    }
+
+    let vocab: serde_json::Map<_, _> = proto
+        .get_pieces()
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.get_piece().to_string(), json!(i)))
+        .collect();
+    let merges: Vec<_> = if model.merges().is_empty() {
+        merges_from_scores(proto)
+    } else {
+        model.merges().to_vec()
+    };
+    let merges: Vec<_> = merges
+        .into_iter()
+        .map(|(left, right)| format!("{} {}", left, right))
+        .collect();
+
+    let add_prefix_space = model.normalizer().add_dummy_prefix;
+    let pre_tokenizer = if model.normalizer().byte_level {
+        json!({
+            "type": "ByteLevel",
+            "add_prefix_space": add_prefix_space,
+            "trim_offsets": true,
+            "use_regex": proto.get_trainer_spec().get_split_by_gpt2_pattern(),
+        })
+    } else {
+        json!({
+            "type": "Metaspace",
+            "replacement": model.normalizer().space_char().to_string(),
+            "add_prefix_space": add_prefix_space,
+            "prepend_scheme": if add_prefix_space { "always" } else { "never" },
+            "split": proto.get_trainer_spec().get_split_by_whitespace(),
+        })
+    };
+    let byte_fallback = proto.get_trainer_spec().get_byte_fallback();
+    let decoder = if byte_fallback {
+        json!({
+            "type": "Sequence",
+            "decoders": [{ "type": "ByteFallback" }, pre_tokenizer.clone()],
+        })
+    } else {
+        pre_tokenizer.clone()
+    };
+    Ok(json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": normalizer_json(model),
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": null,
+        "decoder": decoder,
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": unk_token,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": byte_fallback,
+            "vocab": vocab,
+            "merges": merges,
+        },
+    }))
+}
+
+/// The rule of the normalizer, and whitespace handling except for `SPACE_REP`, which is left to
+/// the pre-tokenizer.
+fn normalizer_json(model: &Model) -> Value {
+    let normalizer = model.normalizer();
+    let mut normalizers = vec![];
+    match &normalizer.rule {
+        Rule::Identity => {}
+        Rule::Nfkd => normalizers.push(json!({ "type": "NFKD" })),
+        Rule::Nfkc => normalizers.push(json!({ "type": "NFKC" })),
+        Rule::Nfc => normalizers.push(json!({ "type": "NFC" })),
+        Rule::NfkcCf => {
+            normalizers.push(json!({ "type": "NFKC" }));
+            normalizers.push(precompiled(&CharsMap::build(&case_folding_rules())));
+            normalizers.push(json!({ "type": "NFKC" }));
+        }
+        Rule::Precompiled { charsmap, .. } => normalizers.push(precompiled(charsmap)),
+    }
+    // the Unicode normalization forms regard all Unicode whitespace as a space, except for
+    // the whitespace kept by `preserve_whitespace`
+    if normalizer.rule.is_unicode_form() {
+        if normalizer.preserve_whitespace {
+            normalizers.push(replace(r"[^\S\t\n\r]", " "));
+        } else {
+            normalizers.push(replace(r"\s", " "));
+        }
+    }
+    if !normalizer.keep_extra_whitespaces {
+        normalizers.push(replace("^ +| +$", ""));
+        normalizers.push(replace(" {2,}", " "));
+    }
+    json!({ "type": "Sequence", "normalizers": normalizers })
+}
+
+fn precompiled(charsmap: &CharsMap) -> Value {
+    json!({
+        "type": "Precompiled",
+        "precompiled_charsmap": base64::encode(charsmap.to_blob()),
+    })
+}
+
+fn replace(pattern: &str, content: &str) -> Value {
+    json!({
+        "type": "Replace",
+        "pattern": { "Regex": pattern },
+        "content": content,
+    })
+}
+
+/// Every split of a normal piece into two pieces, in the descending order of the scores of the
+/// merged pieces, as upstream SentencePiece merges them.
+fn merges_from_scores(proto: &ModelProto) -> Vec<(String, String)> {
+    let pieces = proto.get_pieces();
+    let ids: HashMap<_, _> = pieces
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.get_piece(), i))
+        .collect();
+    let mut merges = vec![];
+    for (i, p) in pieces.iter().enumerate() {
+        if !matches!(
+            p.get_field_type(),
+            ModelProto_SentencePiece_Type::NORMAL | ModelProto_SentencePiece_Type::UNUSED
+        ) {
+            continue;
+        }
+        let piece = p.get_piece();
+        for (split, _) in piece.char_indices().skip(1) {
+            let (left, right) = piece.split_at(split);
+            if let (Some(&l), Some(&r)) = (ids.get(left), ids.get(right)) {
+                merges.push((-p.get_score(), i, l, r, left, right));
+            }
+        }
+    }
+    merges.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0)
+            .unwrap()
+            .then_with(|| (a.1, a.2, a.3).cmp(&(b.1, b.2, b.3)))
+    });
+    merges
+        .into_iter()
+        .map(|m| (m.4.to_string(), m.5.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::train_sample1;
+
+    #[test]
+    fn test_to_tokenizer_json() {
+        let model = train_sample1(200, |_| ()).unwrap();
+        let json = to_tokenizer_json(&model).unwrap();
+
+        let vocab = json["model"]["vocab"].as_object().unwrap();
+        assert_eq!(vocab.len(), 200);
+        for (i, p) in model.proto().get_pieces().iter().enumerate() {
+            assert_eq!(vocab[p.get_piece()], json!(i));
+        }
+        let merges = json["model"]["merges"].as_array().unwrap();
+        assert_eq!(merges.len(), model.merges().len());
+        let (left, right) = &model.merges()[0];
+        assert_eq!(merges[0], json!(format!("{} {}", left, right)));
+
+        assert_eq!(json["model"]["unk_token"], "<unk>");
+        let added: Vec<_> = json["added_tokens"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| (t["id"].as_u64().unwrap(), t["content"].as_str().unwrap()))
+            .collect();
+        assert_eq!(added, vec![(0, "<unk>"), (1, "<s>"), (2, "</s>")]);
+        // the charsmap generated for the name is not needed
+        assert_eq!(json["normalizer"]["normalizers"][0]["type"], "NFKD");
+        assert_eq!(json["pre_tokenizer"]["replacement"], "\u{2581}");
+    }
+
+    #[test]
+    fn test_unicode_forms() {
+        let mut proto = train_sample1(200, |_| ()).unwrap().into_proto();
+        let types = |proto: &ModelProto| -> Vec<_> {
+            let json = to_tokenizer_json(&Model::from_proto(proto.clone()).unwrap()).unwrap();
+            json["normalizer"]["normalizers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|n| n["type"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(types(&proto)[0], "NFKD");
+        proto.mut_normalizer_spec().set_name("nfkc_cf".into());
+        assert_eq!(types(&proto)[..3], ["NFKC", "Precompiled", "NFKC"]);
+        // charsmaps not generated by this crate, e.g. of upstream SentencePiece
+        proto.mut_normalizer_spec().set_generated_charsmap(false);
+        assert_eq!(types(&proto)[0], "Precompiled");
+    }
+
+    #[test]
+    fn test_whitespace_options() {
+        let model = train_sample1(200, |s| s.escape_whitespaces = false).unwrap();
+        let json = to_tokenizer_json(&model).unwrap();
+        assert_eq!(json["pre_tokenizer"]["replacement"], " ");
+        assert_eq!(json["decoder"]["replacement"], " ");
+        let vocab = json["model"]["vocab"].as_object().unwrap();
+        assert!(vocab.keys().any(|p| p.starts_with(' ')));
+        assert!(!vocab.keys().any(|p| p.contains('\u{2581}')));
+
+        let model = train_sample1(200, |s| s.treat_whitespace_as_suffix = true).unwrap();
+        assert!(to_tokenizer_json(&model).is_err());
+        assert!(model.write_hf_tokenizer(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_byte_level() {
+        let model = train_sample1(300, |spec| spec.byte_level = true).unwrap();
+        let json = to_tokenizer_json(&model).unwrap();
+        assert_eq!(json["pre_tokenizer"]["type"], "ByteLevel");
+        assert_eq!(json["decoder"]["type"], "ByteLevel");
+    }
+
+    #[test]
+    fn test_merges_from_scores() {
+        let model = train_sample1(200, |_| ()).unwrap();
+        let merges = merges_from_scores(model.proto());
+        for m in model.merges() {
+            assert!(merges.contains(m), "{:?}", m);
+        }
+        let (left, right) = &merges[0];
+        assert_eq!(
+            model.proto().get_pieces()[3].get_piece(),
+            left.clone() + right
+        );
+    }
+}
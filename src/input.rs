@@ -0,0 +1,475 @@
+//! Training input: expansion of `TrainSpec::inputs` into files, decompression, record formats,
+//! and sentence sampling.
+use crate::return_err;
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Expands `inputs` into files, in a deterministic order.
+///
+/// Each input may be a comma-separated list of
+/// - files,
+/// - directories, whose files are read recursively in the order of their paths,
+/// - glob patterns with `*`, `?` and `[...]`, whose matches are sorted in the same way.
+///
+/// Hidden files are skipped in directories, and in globs unless the pattern starts with `.`.
+pub fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>> {
+    let mut ret = vec![];
+    for input in inputs.iter().flat_map(|s| s.split(',')) {
+        if input.is_empty() {
+            continue;
+        }
+        if is_glob(input) {
+            let paths = glob(input)?;
+            if paths.is_empty() {
+                return_err!("no files match {:?}", input);
+            }
+            for path in paths {
+                walk(&path, &mut ret)?;
+            }
+        } else {
+            let path = Path::new(input);
+            if !path.exists() {
+                return_err!("{:?} does not exist", input);
+            }
+            walk(path, &mut ret)?;
+        }
+    }
+    if ret.is_empty() {
+        return_err!("no input files in {:?}", inputs);
+    }
+    Ok(ret)
+}
+
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Entry names of `dir`, sorted.
+fn read_dir_sorted(dir: &Path) -> Result<Vec<String>> {
+    let mut names = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+/// Pushes `path` if it is a file, or all files under it if it is a directory.
+fn walk(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    for name in read_dir_sorted(path)? {
+        if !is_hidden(&name) {
+            walk(&path.join(name), files)?;
+        }
+    }
+    Ok(())
+}
+
+fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![PathBuf::new()];
+    for comp in Path::new(pattern).components() {
+        let comp_str = comp.as_os_str().to_string_lossy();
+        if !matches!(comp, Component::Normal(_)) || !is_glob(&comp_str) {
+            for path in &mut paths {
+                path.push(comp);
+            }
+            continue;
+        }
+        let comp_pattern: Vec<char> = comp_str.chars().collect();
+        let mut next = vec![];
+        for dir in &paths {
+            let dir_or_cwd = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            if !dir_or_cwd.is_dir() {
+                continue;
+            }
+            for name in read_dir_sorted(dir_or_cwd)? {
+                if is_hidden(&name) && comp_pattern[0] != '.' {
+                    continue;
+                }
+                if wildcard_match(&comp_pattern, &name.chars().collect::<Vec<_>>()) {
+                    next.push(dir.join(name));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|p| p.exists());
+    Ok(paths)
+}
+
+/// A char or a wildcard of a glob pattern
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    /// `?`
+    One,
+    /// `*`
+    Any,
+    /// `[...]`, `[!...]` or `[^...]`, with ranges like `a-z` as `(a, z)`
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    /// Whether a single-char token matches `c`.
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(x) => *x == c,
+            Token::One => true,
+            Token::Any => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+        }
+    }
+}
+
+/// Splits a glob pattern into tokens. `]` right after `[` is a member of the class, and `[`
+/// without `]` is a literal char.
+fn tokenize(pattern: &[char]) -> Vec<Token> {
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < pattern.len() {
+        let token = match pattern[i] {
+            '*' => Token::Any,
+            '?' => Token::One,
+            '[' => {
+                let negated = matches!(pattern.get(i + 1), Some('!') | Some('^'));
+                let start = i + 1 + negated as usize;
+                let end = pattern
+                    .get(start + 1..)
+                    .and_then(|rest| rest.iter().position(|&c| c == ']'))
+                    .map(|j| start + 1 + j);
+                match end {
+                    Some(end) => {
+                        let members = &pattern[start..end];
+                        let mut ranges = vec![];
+                        let mut j = 0;
+                        while j < members.len() {
+                            if j + 2 < members.len() && members[j + 1] == '-' {
+                                ranges.push((members[j], members[j + 2]));
+                                j += 3;
+                            } else {
+                                ranges.push((members[j], members[j]));
+                                j += 1;
+                            }
+                        }
+                        ret.push(Token::Class { negated, ranges });
+                        i = end + 1;
+                        continue;
+                    }
+                    None => Token::Char('['),
+                }
+            }
+            c => Token::Char(c),
+        };
+        ret.push(token);
+        i += 1;
+    }
+    ret
+}
+
+/// Matches a file name with `*`, `?` and `[...]`. `[!...]` and `[^...]` are negated classes.
+///
+/// On a mismatch, only the last `*` is extended by a char, so that the time is at most
+/// proportional to the product of the lengths.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    let tokens = tokenize(pattern);
+    let (mut t, mut n) = (0, 0);
+    // the token after the last `*`, and the position in `name` where it is tried
+    let mut star = None;
+    while n < name.len() {
+        match tokens.get(t) {
+            Some(Token::Any) => {
+                t += 1;
+                star = Some((t, n));
+            }
+            Some(token) if token.matches(name[n]) => {
+                t += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((st, sn)) => {
+                    t = st;
+                    n = sn + 1;
+                    star = Some((st, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[t..].iter().all(|token| *token == Token::Any)
+}
+
+/// Opens `path`, decompressing it if its extension is `.gz` or `.zst`.
+pub fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).map_err(|e| anyhow!("{}: {:?}", e, path))?;
+    let reader: Box<dyn Read> = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Box::new(MultiGzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::new(file)?),
+        _ => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// `TrainerSpec.input_format`: how sentences are stored in the input files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// A sentence per line
+    Text,
+    /// `sentence\tcount` per line, e.g. pre-aggregated word counts, as upstream SentencePiece
+    Tsv,
+    /// A JSON object per line, with the sentence in a string field
+    Jsonl,
+}
+
+impl InputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            InputFormat::Text => "text",
+            InputFormat::Tsv => "tsv",
+            InputFormat::Jsonl => "jsonl",
+        }
+    }
+
+    /// Parses a line into a sentence and its count. `field` is the dot-separated path of the
+    /// sentence in JSON Lines, e.g. `meta.text`.
+    pub fn parse_line(self, line: String, field: &str) -> Result<(String, usize)> {
+        match self {
+            InputFormat::Text => Ok((line, 1)),
+            InputFormat::Tsv => {
+                let (sentence, count) = match line.rfind('\t') {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => {
+                        return_err!("no count in {:?}", line);
+                    }
+                };
+                let count = count
+                    .trim()
+                    .parse()
+                    .map_err(|e| anyhow!("invalid count {:?}: {}", count, e))?;
+                Ok((sentence.to_string(), count))
+            }
+            InputFormat::Jsonl => {
+                let record: Value = serde_json::from_str(&line)?;
+                let value = field
+                    .split('.')
+                    .try_fold(&record, |v, key| v.get(key))
+                    .ok_or_else(|| anyhow!("no field {:?} in {}", field, line))?;
+                match value {
+                    Value::String(sentence) => Ok((sentence.clone(), 1)),
+                    _ => {
+                        return_err!("field {:?} is not a string: {}", field, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(InputFormat::Text),
+            "tsv" => Ok(InputFormat::Tsv),
+            "jsonl" => Ok(InputFormat::Jsonl),
+            _ => Err(anyhow!("unknown input format: {}", s)),
+        }
+    }
+}
+
+/// Bounds the number of sentences kept in memory.
+///
+/// With `shuffle`, sentences are reservoir-sampled uniformly from the whole input, otherwise the
+/// first `size` ones are kept. `size == 0` keeps all.
+pub struct Sampler<T> {
+    size: usize,
+    shuffle: bool,
+    rng: ChaCha8Rng,
+    sampled: Vec<T>,
+    total: usize,
+}
+
+impl<T> Sampler<T> {
+    pub fn new(size: usize, shuffle: bool, seed: u64) -> Self {
+        Self {
+            size,
+            shuffle,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            sampled: vec![],
+            total: 0,
+        }
+    }
+
+    /// Returns false if no more items are needed.
+    pub fn add(&mut self, item: T) -> bool {
+        self.total += 1;
+        if self.size == 0 || self.sampled.len() < self.size {
+            self.sampled.push(item);
+        } else if self.shuffle {
+            let n = self.rng.gen_range(0, self.total);
+            if n < self.size {
+                self.sampled[n] = item;
+            }
+        }
+        self.shuffle || self.size == 0 || self.sampled.len() < self.size
+    }
+
+    /// Number of items added so far.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn into_sampled(self) -> Vec<T> {
+        self.sampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        let m = |p: &str, s: &str| {
+            wildcard_match(
+                &p.chars().collect::<Vec<_>>(),
+                &s.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(m("*.txt", "a.txt"));
+        assert!(m("*.txt", ".txt"));
+        assert!(!m("*.txt", "a.txt.gz"));
+        assert!(m("a?c", "abc"));
+        assert!(!m("a?c", "ac"));
+        assert!(m("shard-[0-3]", "shard-2"));
+        assert!(!m("shard-[0-3]", "shard-4"));
+        assert!(m("shard-[!0-3]", "shard-4"));
+        assert!(m("[]]", "]"));
+        assert!(m("[a", "[a"));
+        assert!(m("*", ""));
+        assert!(m("a*b*c", "aXbYbZc"));
+        assert!(!m("a*b*c", "aXbYbZ"));
+        assert!(m("[!]]x", "ax"));
+        assert!(!m("[!]]x", "]x"));
+        // no exponential backtracking
+        let name = "a".repeat(100);
+        assert!(!m(&format!("{}b", "*a".repeat(30)), &name));
+        assert!(m(&"*a".repeat(30), &name));
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let s = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let p = |v: &[&str]| v.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            expand_inputs(&s(&[
+                "tests/sample[0-2].txt",
+                "tests/sample4.txt,tests/sample3.txt"
+            ]))
+            .unwrap(),
+            p(&[
+                "tests/sample0.txt",
+                "tests/sample1.txt",
+                "tests/sample2.txt",
+                "tests/sample4.txt",
+                "tests/sample3.txt",
+            ])
+        );
+        let golden = expand_inputs(&s(&["tests/golden"])).unwrap();
+        assert!(golden.contains(&PathBuf::from("tests/golden/input.txt")));
+        assert!(golden.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            expand_inputs(&s(&["tests/*/input.txt"])).unwrap(),
+            p(&["tests/golden/input.txt"])
+        );
+
+        assert!(expand_inputs(&s(&["tests/nothing*.txt"])).is_err());
+        assert!(expand_inputs(&s(&["tests/nothing.txt"])).is_err());
+        assert!(expand_inputs(&s(&[","])).is_err());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let parse = |format: InputFormat, line: &str| format.parse_line(line.into(), "meta.text");
+        assert_eq!(
+            parse(InputFormat::Text, "a\tb").unwrap(),
+            ("a\tb".into(), 1)
+        );
+        assert_eq!(
+            parse(InputFormat::Tsv, "a\tb\t12").unwrap(),
+            ("a\tb".into(), 12)
+        );
+        assert!(parse(InputFormat::Tsv, "a b").is_err());
+        assert!(parse(InputFormat::Tsv, "a\t-1").is_err());
+        assert_eq!(
+            parse(InputFormat::Jsonl, r#"{"meta": {"text": "a\nb"}, "id": 1}"#).unwrap(),
+            ("a\nb".into(), 1)
+        );
+        assert!(parse(InputFormat::Jsonl, r#"{"text": "a"}"#).is_err());
+        assert!(parse(InputFormat::Jsonl, r#"{"meta": {"text": 1}}"#).is_err());
+        assert!(parse(InputFormat::Jsonl, "a").is_err());
+    }
+
+    #[test]
+    fn test_open() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+        let text = fs::read_to_string("tests/sample1.txt").unwrap();
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(text.as_bytes()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let gz_path = dir.path().join("sample1.txt.gz");
+        fs::write(&gz_path, gz.finish().unwrap()).unwrap();
+        let zst_path = dir.path().join("sample1.txt.zst");
+        fs::write(&zst_path, zstd::encode_all(text.as_bytes(), 0).unwrap()).unwrap();
+        for path in &[Path::new("tests/sample1.txt"), &gz_path, &zst_path] {
+            let mut read = String::new();
+            open(path).unwrap().read_to_string(&mut read).unwrap();
+            assert_eq!(read, text, "{:?}", path);
+        }
+        assert!(open(Path::new("tests/nothing.txt")).is_err());
+    }
+
+    #[test]
+    fn test_sampler() {
+        let sample = |size, shuffle, seed| {
+            let mut sampler = Sampler::new(size, shuffle, seed);
+            for i in 0..1000 {
+                if !sampler.add(i) {
+                    break;
+                }
+            }
+            (sampler.total(), sampler.into_sampled())
+        };
+        assert_eq!(sample(0, true, 0), (1000, (0..1000).collect()));
+        assert_eq!(sample(10, false, 0), (10, (0..10).collect()));
+
+        let (total, sampled) = sample(10, true, 0);
+        assert_eq!(total, 1000);
+        assert_eq!(sampled.len(), 10);
+        assert!(sampled.iter().any(|&i| i >= 10));
+        assert_eq!(sample(10, true, 0).1, sampled);
+        assert_ne!(sample(10, true, 1).1, sampled);
+    }
+}
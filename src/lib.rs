@@ -0,0 +1,24 @@
+// tests set up specs by changing the fields of their defaults
+#![cfg_attr(test, allow(clippy::field_reassign_with_default))]
+mod charsmap;
+mod decode;
+mod encode;
+mod gpt2;
+mod hf;
+mod input;
+mod model;
+mod norm;
+pub mod protos;
+mod spec;
+mod split;
+mod train;
+mod util;
+
+pub use charsmap::CharsMap;
+pub use decode::Decoder;
+pub use encode::Encoder;
+pub use input::InputFormat;
+pub use model::{escape_piece, unescape_piece, Model};
+pub use norm::{Normalizer, Rule, SPACE_REP};
+pub use spec::TrainSpec;
+pub use train::Trainer;
@@ -1,23 +1,12 @@
-#![feature(map_first_last)]
-#[cfg(test)]
-extern crate quickcheck;
-#[cfg(test)]
-#[macro_use(quickcheck)]
-extern crate quickcheck_macros;
-mod decode;
-mod model;
-mod norm;
-mod protos;
-mod train;
-mod util;
-use env_logger;
-mod spec;
+use bpe::{escape_piece, unescape_piece, Model, TrainSpec, Trainer};
 use log::{self, LevelFilter};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::Local;
 use clap::Clap;
-use std::io::prelude::*;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::str::FromStr;
 
 #[derive(Clap)]
 struct Opts {
@@ -27,22 +16,100 @@ struct Opts {
     verbose: u32,
 }
 
+// parsed once, so the size of `TrainSpec` does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clap)]
 enum SubCmd {
-    Train(spec::TrainSpec),
+    Train(TrainSpec),
     Encode(EncodeOpts),
-    Decode(decode::DecodeOpts),
+    Decode(DecodeOpts),
+    Export(ExportOpts),
+    Import(ImportOpts),
 }
 
 #[derive(Clap)]
 struct EncodeOpts {
-    #[clap(short, long, default_value = "out.bin")]
-    out: String,
-    #[clap(short, long, default_value = "vocab.bpe")]
-    vocab_path: String,
+    #[clap(short, long)]
+    model_path: String,
+    /// Output file. Defaults to stdout.
+    #[clap(short, long)]
+    out: Option<String>,
+    /// Pieces are separated by spaces. If pieces may have whitespace, i.e. with
+    /// `preserve_whitespace` or without `escape_whitespaces`, it is escaped as `\s`, `\t`, `\n`
+    /// and `\r`, and a backslash as `\\`.
+    #[clap(long, default_value = "piece", possible_values = &["piece", "id"])]
+    output_format: TokenFormat,
     input: String,
 }
 
+#[derive(Clap)]
+struct DecodeOpts {
+    #[clap(short, long)]
+    model_path: String,
+    /// Output file. Defaults to stdout.
+    #[clap(short, long)]
+    out: Option<String>,
+    /// Pieces are escaped as the output of `encode`.
+    #[clap(long, default_value = "piece", possible_values = &["piece", "id"])]
+    input_format: TokenFormat,
+    input: String,
+}
+
+#[derive(Clap)]
+struct ExportOpts {
+    #[clap(short, long)]
+    model_path: String,
+    /// Output file. Defaults to stdout.
+    #[clap(short, long)]
+    out: Option<String>,
+    #[clap(long, default_value = "hf", possible_values = &["hf"])]
+    format: ExportFormat,
+}
+
+/// Converts a GPT-2 style model into `<model_prefix>.model`, `.vocab` and `.merges`.
+#[derive(Clap)]
+struct ImportOpts {
+    #[clap(long)]
+    vocab: String,
+    #[clap(long)]
+    merges: String,
+    #[clap(short, long)]
+    model_prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenFormat {
+    Piece,
+    Id,
+}
+
+impl FromStr for TokenFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "piece" => Ok(TokenFormat::Piece),
+            "id" => Ok(TokenFormat::Id),
+            _ => Err(anyhow!("unknown token format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    /// `tokenizer.json` of Hugging Face `tokenizers`
+    Hf,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hf" => Ok(ExportFormat::Hf),
+            _ => Err(anyhow!("unknown export format: {}", s)),
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let spec: Opts = Opts::parse();
     let level = match spec.verbose {
@@ -67,9 +134,123 @@ fn main() -> Result<()> {
         .init();
 
     match spec.subcmd {
-        SubCmd::Train(spec) => train::train(spec)?,
-        SubCmd::Encode(spec) => {}
-        SubCmd::Decode(spec) => decode::decode(spec)?,
+        SubCmd::Train(spec) => train(spec)?,
+        SubCmd::Encode(spec) => encode(spec)?,
+        SubCmd::Decode(spec) => decode(spec)?,
+        SubCmd::Export(spec) => export(spec)?,
+        SubCmd::Import(spec) => import(spec)?,
+    }
+    Ok(())
+}
+
+fn train(spec: TrainSpec) -> Result<()> {
+    let prefix = spec.model_prefix.clone();
+    let model = Trainer::new(spec).train()?;
+    save(&model, prefix)
+}
+
+fn import(spec: ImportOpts) -> Result<()> {
+    let model = Model::load_gpt2(&spec.vocab, &spec.merges)?;
+    log::info!("Loaded {} and {}", &spec.vocab, &spec.merges);
+    save(&model, spec.model_prefix)
+}
+
+/// Saves `<prefix>.vocab`, `<prefix>.merges` and `<prefix>.model`.
+fn save(model: &Model, prefix: String) -> Result<()> {
+    let path = prefix.clone() + ".vocab";
+    model.save_vocab(&path)?;
+    log::info!("Saved vocab to {}", path);
+
+    let path = prefix.clone() + ".merges";
+    model.save_merges(&path)?;
+    log::info!("Saved merges to {}", path);
+
+    let path = prefix + ".model";
+    model.save(&path)?;
+    log::info!("Saved model to {}", path);
+    Ok(())
+}
+
+fn open_output(path: &Option<String>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
+fn encode(spec: EncodeOpts) -> Result<()> {
+    let model = Model::load(&spec.model_path)?;
+    log::info!("Loaded model from {}", &spec.model_path);
+
+    let mut input = BufReader::new(File::open(&spec.input)?);
+    let mut out = open_output(&spec.out)?;
+    let escape = model.escapes_pieces();
+    let mut write = |text: &str| -> Result<()> {
+        let encoded = model.encode(text);
+        let tokens: Vec<_> = match spec.output_format {
+            TokenFormat::Piece if escape => encoded.iter().map(|(p, _)| escape_piece(p)).collect(),
+            TokenFormat::Piece => encoded.into_iter().map(|(p, _)| p).collect(),
+            TokenFormat::Id => encoded.into_iter().map(|(_, i)| i.to_string()).collect(),
+        };
+        writeln!(out, "{}", tokens.join(" "))?;
+        Ok(())
+    };
+    if model.normalizer().preserve_whitespace {
+        // the whole input is a document, as in training
+        let mut document = String::new();
+        input.read_to_string(&mut document)?;
+        write(&document)?;
+    } else {
+        for line in input.lines() {
+            write(&line?)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn decode(spec: DecodeOpts) -> Result<()> {
+    let model = Model::load(&spec.model_path)?;
+    log::info!("Loaded model from {}", &spec.model_path);
+
+    let input = BufReader::new(File::open(&spec.input)?);
+    let mut out = open_output(&spec.out)?;
+    let escape = model.escapes_pieces();
+    for line in input.lines() {
+        let line = line?;
+        let tokens = line.split_whitespace();
+        let text = match spec.input_format {
+            TokenFormat::Piece if escape => {
+                let pieces = tokens.map(unescape_piece).collect::<Result<Vec<_>>>()?;
+                model.decode_pieces(pieces.iter().map(String::as_str))
+            }
+            TokenFormat::Piece => model.decode_pieces(tokens),
+            TokenFormat::Id => {
+                let ids = tokens
+                    .map(|t| t.parse::<usize>().map_err(|e| anyhow!("{}: {:?}", e, t)))
+                    .collect::<Result<Vec<_>>>()?;
+                model.decode_ids(&ids)?
+            }
+        };
+        if model.normalizer().preserve_whitespace {
+            // documents have their own newlines
+            write!(out, "{}", text)?;
+        } else {
+            writeln!(out, "{}", text)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn export(spec: ExportOpts) -> Result<()> {
+    let model = Model::load(&spec.model_path)?;
+    log::info!("Loaded model from {}", &spec.model_path);
+
+    let mut out = open_output(&spec.out)?;
+    match spec.format {
+        ExportFormat::Hf => model.write_hf_tokenizer(&mut out)?,
     }
+    out.flush()?;
     Ok(())
 }
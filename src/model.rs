@@ -1,14 +1,299 @@
-use crate::protos::sentencepiece_model::ModelProto;
-use anyhow::Result;
-use protobuf::{self, Message};
-use std::fs::File;
-use std::io::BufWriter;
+use crate::decode::Decoder;
+use crate::encode::Encoder;
+use crate::gpt2;
+use crate::hf;
+use crate::norm::Normalizer;
+use crate::protos::sentencepiece::{SentencePieceText, SentencePieceText_SentencePiece};
+use crate::protos::sentencepiece_model::{ModelProto, TrainerSpec_ModelType};
+use anyhow::{anyhow, Result};
+use protobuf::{self, CodedInputStream, CodedOutputStream, Message};
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{prelude::*, BufReader, BufWriter};
 use std::path::Path;
 
+/// Field number of the merges in `ModelProto`, stored as the extension
+///
+/// ```proto
+/// message Merge {
+///   optional string left = 1;
+///   optional string right = 2;
+/// }
+/// extend ModelProto {
+///   repeated Merge merges = 200;
+/// }
+/// ```
+///
+/// Upstream SentencePiece ignores it, and encodes with scores instead.
+const MERGES_FIELD: u32 = 200;
+
 impl ModelProto {
+    /// `(left, right)` merges in the order learned by the trainer. Empty if the model has none.
+    pub fn get_merges(&self) -> Result<Vec<(String, String)>> {
+        let values = match self.get_unknown_fields().get(MERGES_FIELD) {
+            Some(values) => values,
+            None => return Ok(vec![]),
+        };
+        values
+            .length_delimited
+            .iter()
+            .map(|bytes| {
+                let mut is = CodedInputStream::from_bytes(bytes);
+                let (mut left, mut right) = (None, None);
+                while !is.eof()? {
+                    let (field, wire_type) = is.read_tag_unpack()?;
+                    match field {
+                        1 => left = Some(is.read_string()?),
+                        2 => right = Some(is.read_string()?),
+                        _ => is.skip_field(wire_type)?,
+                    }
+                }
+                match (left, right) {
+                    (Some(left), Some(right)) => Ok((left, right)),
+                    _ => Err(anyhow!("incomplete merge in the model")),
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_merges(&mut self, merges: &[(String, String)]) {
+        let fields = self.mut_unknown_fields();
+        if let Some(fields) = &mut fields.fields {
+            fields.remove(&MERGES_FIELD);
+        }
+        for (left, right) in merges {
+            let mut bytes = vec![];
+            let mut os = CodedOutputStream::vec(&mut bytes);
+            // writing to `Vec` never fails
+            os.write_string(1, left).unwrap();
+            os.write_string(2, right).unwrap();
+            os.flush().unwrap();
+            drop(os);
+            fields.add_length_delimited(MERGES_FIELD, bytes);
+        }
+    }
+
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let mut f = BufWriter::new(File::create(path)?);
         self.write_to_writer(&mut f)?;
         Ok(())
     }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut f = BufReader::new(File::open(path)?);
+        Ok(protobuf::parse_from_reader(&mut f)?)
+    }
+}
+
+/// A trained model, ready to encode and decode text.
+pub struct Model {
+    proto: ModelProto,
+    merges: Vec<(String, String)>,
+    normalizer: Normalizer,
+    encoder: Encoder,
+    decoder: Decoder,
+}
+
+impl Model {
+    /// Models without `trainer_spec` or `normalizer_spec` are regarded as trained with the
+    /// default `TrainSpec`.
+    pub fn from_proto(proto: ModelProto) -> Result<Self> {
+        if proto.has_trainer_spec() {
+            let model_type = proto.get_trainer_spec().get_model_type();
+            if model_type != TrainerSpec_ModelType::BPE {
+                return Err(anyhow!("Unsupported model type: {:?}", model_type));
+            }
+        }
+        let mut normalizer = if proto.has_normalizer_spec() {
+            Normalizer::from_spec(proto.get_normalizer_spec())?
+        } else {
+            Normalizer::default()
+        };
+        normalizer.treat_whitespace_as_suffix =
+            proto.get_trainer_spec().get_treat_whitespace_as_suffix();
+        let merges = proto.get_merges()?;
+        Ok(Self {
+            normalizer,
+            encoder: Encoder::new(&proto, &merges),
+            merges,
+            decoder: Decoder::new(&proto),
+            proto,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_proto(ModelProto::load(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.proto.save(path)
+    }
+
+    /// Imports a GPT-2 style model. See `gpt2::parse` for how pieces are typed and scored.
+    pub fn load_gpt2<P: AsRef<Path>, Q: AsRef<Path>>(
+        vocab_path: P,
+        merges_path: Q,
+    ) -> Result<Self> {
+        let vocab = fs::read_to_string(vocab_path)?;
+        let merges = fs::read_to_string(merges_path)?;
+        Self::from_proto(gpt2::parse(&vocab, &merges)?)
+    }
+
+    /// Writes `vocab.json` and `merges.txt` of GPT-2 style models.
+    pub fn save_gpt2<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        vocab_path: P,
+        merges_path: Q,
+    ) -> Result<()> {
+        let mut f = BufWriter::new(File::create(vocab_path)?);
+        gpt2::write_vocab(&self.proto, &mut f)?;
+        f.flush()?;
+        let mut f = BufWriter::new(File::create(merges_path)?);
+        gpt2::write_merges(&self.proto, &mut f)?;
+        f.flush()?;
+        Ok(())
+    }
+
+    /// Writes `piece\tscore` per line, in the order of ids. Pieces are escaped by `escape_piece`
+    /// if `escapes_pieces`.
+    pub fn save_vocab<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut f = BufWriter::new(File::create(path)?);
+        for p in self.proto.get_pieces() {
+            writeln!(f, "{}\t{}", self.format_piece(p.get_piece()), p.get_score())?;
+        }
+        Ok(())
+    }
+
+    /// Writes `left\tright` per line, in the order of merges. Pieces are escaped by
+    /// `escape_piece` if `escapes_pieces`.
+    pub fn save_merges<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut f = BufWriter::new(File::create(path)?);
+        for (left, right) in &self.merges {
+            let (left, right) = (self.format_piece(left), self.format_piece(right));
+            writeln!(f, "{}\t{}", left, right)?;
+        }
+        Ok(())
+    }
+
+    /// Whether pieces may have whitespace, which is escaped in `.vocab`, `.merges` and the piece
+    /// format of the CLI.
+    pub fn escapes_pieces(&self) -> bool {
+        self.normalizer.preserve_whitespace || !self.normalizer.escape_whitespaces
+    }
+
+    fn format_piece<'a>(&self, piece: &'a str) -> Cow<'a, str> {
+        if self.escapes_pieces() {
+            Cow::Owned(escape_piece(piece))
+        } else {
+            Cow::Borrowed(piece)
+        }
+    }
+
+    /// Writes the model as `tokenizer.json` of Hugging Face `tokenizers`. Fails if the model
+    /// cannot be represented there, e.g. with `treat_whitespace_as_suffix`.
+    pub fn write_hf_tokenizer<W: Write>(&self, mut w: W) -> Result<()> {
+        serde_json::to_writer_pretty(&mut w, &hf::to_tokenizer_json(self)?)?;
+        writeln!(w)?;
+        Ok(())
+    }
+
+    /// `(left, right)` merges in the order learned by the trainer. Empty for upstream models.
+    pub fn merges(&self) -> &[(String, String)] {
+        &self.merges
+    }
+
+    pub fn proto(&self) -> &ModelProto {
+        &self.proto
+    }
+
+    pub fn into_proto(self) -> ModelProto {
+        self.proto
+    }
+
+    pub fn normalizer(&self) -> &Normalizer {
+        &self.normalizer
+    }
+
+    pub fn set_normalizer(&mut self, normalizer: Normalizer) {
+        self.normalizer = normalizer;
+    }
+
+    /// Normalizes `text` and encodes it into `(piece, id)` pairs.
+    pub fn encode(&self, text: &str) -> Vec<(String, usize)> {
+        self.encoder.encode(&self.normalizer.to_chars(text))
+    }
+
+    /// Normalizes and encodes `text`, with the alignment of pieces to it: `begin` and `end` are
+    /// byte offsets in `text`, and `surface` is the text between them, as upstream SentencePiece.
+    /// See `Normalizer::to_chars_with_offsets` for which piece the removed text belongs to.
+    pub fn encode_to_proto(&self, text: &str) -> SentencePieceText {
+        let (chars, offsets) = self.normalizer.to_chars_with_offsets(text);
+        let mut ret = SentencePieceText::new();
+        ret.set_text(text.to_string());
+        for (piece, id, span) in self.encoder.encode_with_spans(&chars) {
+            let (begin, end) = (offsets[span.start], offsets[span.end]);
+            let mut p = SentencePieceText_SentencePiece::new();
+            p.set_piece(piece);
+            p.set_id(id as u32);
+            p.set_surface(text[begin..end].to_string());
+            p.set_begin(begin as u32);
+            p.set_end(end as u32);
+            ret.mut_pieces().push(p);
+        }
+        ret
+    }
+
+    pub fn encode_as_pieces(&self, text: &str) -> Vec<String> {
+        self.encode(text).into_iter().map(|(p, _)| p).collect()
+    }
+
+    pub fn encode_as_ids(&self, text: &str) -> Vec<usize> {
+        self.encode(text).into_iter().map(|(_, i)| i).collect()
+    }
+
+    pub fn decode_ids(&self, ids: &[usize]) -> Result<String> {
+        self.decoder.decode_ids(ids)
+    }
+
+    pub fn decode_pieces<'a>(&self, pieces: impl IntoIterator<Item = &'a str>) -> String {
+        self.decoder.decode_pieces(pieces)
+    }
+}
+
+/// Escapes backslashes and whitespace in `piece` as `\\`, `\s`, `\t`, `\n` and `\r`, so that
+/// pieces are separated by spaces and lines in text formats.
+pub fn escape_piece(piece: &str) -> String {
+    let mut ret = String::with_capacity(piece.len());
+    for c in piece.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            ' ' => ret.push_str("\\s"),
+            '\t' => ret.push_str("\\t"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Inverse of `escape_piece`
+pub fn unescape_piece(token: &str) -> Result<String> {
+    let mut ret = String::with_capacity(token.len());
+    let mut chars = token.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        ret.push(match chars.next() {
+            Some('\\') => '\\',
+            Some('s') => ' ',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            _ => return Err(anyhow!("invalid escape in piece: {:?}", token)),
+        });
+    }
+    Ok(ret)
 }
@@ -1,43 +1,1094 @@
+use crate::charsmap::CharsMap;
+use crate::protos::sentencepiece_model::{ModelProto, NormalizerSpec};
+use crate::return_err;
 use crate::spec::TrainSpec;
+use crate::util;
+use anyhow::{anyhow, Result};
+use caseless::Caseless;
+use once_cell::sync::Lazy;
+use protobuf::Message;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::iter;
+use std::sync::Mutex;
+use unicode_normalization::char::canonical_combining_class;
 use unicode_normalization::UnicodeNormalization;
 
 pub const SPACE_REP: char = '\u{2581}';
 
-/// 1. normalize wiht NFKD
-/// 2. replace whitespace to U+2581
-pub fn to_chars(mut s: &str, spec: &TrainSpec) -> Vec<char> {
-    let mut ret = vec![SPACE_REP];
-    let mut is_prev_space = !spec.keep_extra_whitespaces;
+/// `NormalizerSpec.name` of `Rule::Nfkd`
+pub const NFKD: &str = "nfkd";
+/// `NormalizerSpec.name` of `Rule::Nfkc`
+pub const NFKC: &str = "nfkc";
+/// `NormalizerSpec.name` of `Rule::Nfc`
+pub const NFC: &str = "nfc";
+/// `NormalizerSpec.name` of `Rule::NfkcCf`
+pub const NFKC_CF: &str = "nfkc_cf";
+/// `NormalizerSpec.name` of `Rule::Identity`
+pub const IDENTITY: &str = "identity";
+/// `NormalizerSpec.name` of rules with `normalization_rule_tsv`, as upstream SentencePiece
+pub const USER_DEFINED: &str = "user_defined";
 
-    if !spec.keep_extra_whitespaces {
-        s = s.trim();
+/// Field numbers of the flags in `NormalizerSpec`, stored as the extensions
+///
+/// ```proto
+/// extend NormalizerSpec {
+///   optional bool byte_level = 200;
+///   optional bool preserve_whitespace = 201;
+///   // precompiled_charsmap is generated by this crate from name
+///   optional bool generated_charsmap = 202;
+/// }
+/// ```
+const BYTE_LEVEL_FIELD: u32 = 200;
+const PRESERVE_WHITESPACE_FIELD: u32 = 201;
+const GENERATED_CHARSMAP_FIELD: u32 = 202;
+
+impl NormalizerSpec {
+    pub fn get_byte_level(&self) -> bool {
+        util::get_bool_extension(self.get_unknown_fields(), BYTE_LEVEL_FIELD)
+    }
+
+    pub fn set_byte_level(&mut self, v: bool) {
+        util::set_bool_extension(self.mut_unknown_fields(), BYTE_LEVEL_FIELD, v)
+    }
+
+    pub fn get_preserve_whitespace(&self) -> bool {
+        util::get_bool_extension(self.get_unknown_fields(), PRESERVE_WHITESPACE_FIELD)
+    }
+
+    pub fn set_preserve_whitespace(&mut self, v: bool) {
+        util::set_bool_extension(self.mut_unknown_fields(), PRESERVE_WHITESPACE_FIELD, v)
+    }
+
+    pub fn get_generated_charsmap(&self) -> bool {
+        util::get_bool_extension(self.get_unknown_fields(), GENERATED_CHARSMAP_FIELD)
+    }
+
+    pub fn set_generated_charsmap(&mut self, v: bool) {
+        util::set_bool_extension(self.mut_unknown_fields(), GENERATED_CHARSMAP_FIELD, v)
+    }
+}
+
+/// Whitespace kept as it is with `Normalizer::preserve_whitespace`
+fn is_preserved_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r')
+}
+
+/// The char which whitespace is replaced with: `SPACE_REP`, U+0020 without escaping, or `Ġ` in
+/// byte-level mode.
+pub fn space_char(byte_level: bool, escape_whitespaces: bool) -> char {
+    if byte_level {
+        byte_to_char(b' ')
+    } else if escape_whitespaces {
+        SPACE_REP
+    } else {
+        ' '
+    }
+}
+
+/// Maps a byte to a char of the 256-symbol alphabet of GPT-2: printable Latin-1 chars are kept,
+/// and the other bytes are shifted to U+0100.. in order, e.g. a space is `Ġ` (U+0120).
+pub fn byte_to_char(b: u8) -> char {
+    let c = match b {
+        b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff => b as u32,
+        0x00..=0x20 => 0x100 + b as u32,
+        0x7f..=0xa0 => 0x100 + 0x21 + (b - 0x7f) as u32,
+        0xad => 0x100 + 0x21 + 0x22,
+    };
+    // all values are valid chars
+    std::char::from_u32(c).unwrap()
+}
+
+/// Chars of a symbol as they appear in the output of `Normalizer::to_chars`. The symbol is not
+/// normalized.
+pub fn symbol_chars(s: &str, byte_level: bool) -> Vec<char> {
+    if byte_level {
+        s.bytes().map(byte_to_char).collect()
+    } else {
+        s.chars().collect()
+    }
+}
+
+/// Splits `chars` into the leftmost longest matches of `symbols` and the rest. Matches have the
+/// index of the symbol.
+pub fn split_symbols<'a>(
+    chars: &'a [char],
+    symbols: &[Vec<char>],
+) -> Vec<(&'a [char], Option<usize>)> {
+    let mut ret = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let matched = symbols
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !s.is_empty() && chars[i..].starts_with(s))
+            .max_by_key(|(j, s)| (s.len(), std::cmp::Reverse(*j)));
+        match matched {
+            Some((j, s)) => {
+                if start < i {
+                    ret.push((&chars[start..i], None));
+                }
+                ret.push((&chars[i..i + s.len()], Some(j)));
+                i += s.len();
+                start = i;
+            }
+            None => i += 1,
+        }
     }
+    if start < chars.len() {
+        ret.push((&chars[start..], None));
+    }
+    ret
+}
+
+/// Piece of type `BYTE` for byte fallback, e.g. `<0x0A>`
+pub fn byte_piece(b: u8) -> String {
+    format!("<0x{:02X}>", b)
+}
+
+/// Inverse of `byte_piece`
+pub fn parse_byte_piece(s: &str) -> Option<u8> {
+    if s.len() != 6 || !s.starts_with("<0x") || !s.ends_with('>') {
+        return None;
+    }
+    u8::from_str_radix(&s[3..5], 16).ok()
+}
+
+/// Inverse of `byte_to_char`
+pub fn char_to_byte(c: char) -> Option<u8> {
+    match c as u32 {
+        c @ 0x21..=0x7e | c @ 0xa1..=0xac | c @ 0xae..=0xff => Some(c as u8),
+        c @ 0x100..=0x120 => Some((c - 0x100) as u8),
+        c @ 0x121..=0x142 => Some((c - 0x121) as u8 + 0x7f),
+        0x143 => Some(0xad),
+        _ => None,
+    }
+}
+
+/// Normalization rule applied before whitespace handling.
+///
+/// The Unicode normalization forms are applied to each run of a starter and the chars combining
+/// with it (see `Rule::runs`), which gives the same result as normalizing the whole text. The
+/// `precompiled_charsmap` written for upstream SentencePiece gives the same result unless more
+/// than one combining mark of a run is out of the canonical order (see `unicode_rules`).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Rule {
+    Identity,
+    #[default]
+    Nfkd,
+    Nfkc,
+    Nfc,
+    /// NFKC, the full case folding of Unicode and NFKC again, as `NFKC_Casefold` except that
+    /// default ignorable code points are not removed.
+    NfkcCf,
+    /// `precompiled_charsmap` loaded from a model, e.g. `nmt_nfkc` of upstream SentencePiece.
+    Precompiled { name: String, charsmap: CharsMap },
+}
 
-    for c in s.nfkd() {
-        if c.is_whitespace() {
-            if !is_prev_space {
-                ret.push(SPACE_REP);
-                is_prev_space = !spec.keep_extra_whitespaces;
+impl Rule {
+    /// The rule named `name` in `NormalizerSpec.name`, except for `Rule::Precompiled`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            IDENTITY => Some(Rule::Identity),
+            NFKD => Some(Rule::Nfkd),
+            NFKC => Some(Rule::Nfkc),
+            NFC => Some(Rule::Nfc),
+            NFKC_CF => Some(Rule::NfkcCf),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Rule::Identity => IDENTITY,
+            Rule::Nfkd => NFKD,
+            Rule::Nfkc => NFKC,
+            Rule::Nfc => NFC,
+            Rule::NfkcCf => NFKC_CF,
+            Rule::Precompiled { name, .. } => name,
+        }
+    }
+
+    /// `Rule::Precompiled` applying `user_rules` after `self`, e.g. from `parse_rule_tsv`.
+    ///
+    /// The user rules are applied to the output of each char normalized by `self`, and to the
+    /// input with the longest match, taking precedence over `self` for the same source.
+    pub fn with_user_rules(&self, user_rules: BTreeMap<String, String>) -> Result<Self> {
+        let mut rules = match self {
+            Rule::Identity => BTreeMap::new(),
+            Rule::Precompiled { name, .. } => {
+                return_err!("user rules cannot be added to precompiled {:?}", name);
             }
+            rule => unicode_rules(rule, false),
+        };
+        if let Some(src) = user_rules.keys().find(|s| s.is_empty() || s.contains('\0')) {
+            return_err!("invalid source of a user rule: {:?}", src);
+        }
+        let user = CharsMap::build(&user_rules);
+        for tgt in rules.values_mut() {
+            *tgt = apply_charsmap(&user, tgt, false)
+                .into_iter()
+                .map(|(c, _)| c)
+                .collect();
+        }
+        rules.extend(user_rules);
+        Ok(Rule::Precompiled {
+            name: USER_DEFINED.to_string(),
+            charsmap: CharsMap::build(&rules),
+        })
+    }
+
+    /// Whether the rule is a Unicode normalization form, which maps any whitespace to U+0020.
+    pub fn is_unicode_form(&self) -> bool {
+        matches!(self, Rule::Nfkd | Rule::Nfkc | Rule::Nfc | Rule::NfkcCf)
+    }
+
+    /// Pushes `s` normalized with a Unicode normalization form.
+    fn push_normalized(&self, s: &str, out: &mut String) {
+        match self {
+            Rule::Nfkd => out.extend(s.nfkd()),
+            Rule::Nfkc => out.extend(s.nfkc()),
+            Rule::Nfc => out.extend(s.nfc()),
+            Rule::NfkcCf => out.extend(s.nfkc().default_case_fold().nfkc()),
+            _ => out.push_str(s),
+        }
+    }
+
+    /// Splits `s` into runs with the byte offsets, each of which is a starter followed by the
+    /// chars which may be reordered or composed with it. Normalizing the runs independently is
+    /// the same as normalizing `s`.
+    fn runs<'a>(&self, s: &'a str) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+        let compatible = !matches!(self, Rule::Nfc);
+        let mut starts: Vec<_> = s
+            .char_indices()
+            .filter(move |&(i, c)| i == 0 || starts_run(c, compatible))
+            .map(|(i, _)| i)
+            .collect();
+        starts.push(s.len());
+        (0..starts.len() - 1).map(move |i| (starts[i], &s[starts[i]..starts[i + 1]]))
+    }
+}
+
+/// Whether no char before `c` is reordered or composed with it. The first char of the
+/// decomposition of `c` must be a starter which never composes with a preceding char.
+fn starts_run(c: char, compatible: bool) -> bool {
+    let first = if c.is_ascii() {
+        c
+    } else if compatible {
+        iter::once(c).nfkd().next().unwrap_or(c)
+    } else {
+        iter::once(c).nfd().next().unwrap_or(c)
+    };
+    canonical_combining_class(first) == 0 && !UNICODE_TABLES.composes_backward.contains(&first)
+}
+
+/// Tables of the Unicode normalization forms, computed once from all chars.
+struct UnicodeTables {
+    /// Chars changed by any of the forms or case folding, and whitespace
+    changed: Vec<char>,
+    /// Starters which are the last char of a canonical decomposition, e.g. Hangul vowel and
+    /// trailing consonant jamo. Some of them may not actually compose.
+    composes_backward: HashSet<char>,
+    /// Combining marks in canonical decompositions
+    marks: BTreeSet<char>,
+    /// Chars by their canonical decompositions of more than one char
+    decompositions: HashMap<String, Vec<char>>,
+}
+
+static UNICODE_TABLES: Lazy<UnicodeTables> = Lazy::new(|| {
+    let mut changed = vec![];
+    let mut composes_backward = HashSet::new();
+    let mut marks = BTreeSet::new();
+    let mut decompositions = HashMap::<_, Vec<_>>::new();
+    for c in (0..=0x10ffff).filter_map(std::char::from_u32) {
+        let unchanged = |chars: &mut dyn Iterator<Item = char>| chars.eq(iter::once(c));
+        if !c.is_whitespace()
+            && unchanged(&mut iter::once(c).nfkd())
+            && unchanged(&mut iter::once(c).default_case_fold())
+        {
+            continue;
+        }
+        changed.push(c);
+        let decomposed: Vec<_> = iter::once(c).nfd().collect();
+        if let [_, .., last] = decomposed[..] {
+            if canonical_combining_class(last) == 0 {
+                composes_backward.insert(last);
+            }
+            marks.extend(
+                decomposed
+                    .iter()
+                    .filter(|&&c| canonical_combining_class(c) != 0),
+            );
+            decompositions
+                .entry(decomposed.into_iter().collect())
+                .or_default()
+                .push(c);
+        }
+    }
+    UnicodeTables {
+        changed,
+        composes_backward,
+        marks,
+        decompositions,
+    }
+});
+
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    pub keep_extra_whitespaces: bool,
+    pub rule: Rule,
+    /// Maps normalized text to UTF-8 bytes with `byte_to_char`, after whitespace handling.
+    pub byte_level: bool,
+    /// Adds whitespace at the beginning, or at the end with `treat_whitespace_as_suffix`.
+    pub add_dummy_prefix: bool,
+    /// Replaces whitespace with `SPACE_REP`. Otherwise it is U+0020.
+    pub escape_whitespaces: bool,
+    /// `TrainerSpec.treat_whitespace_as_suffix`, which is not in `NormalizerSpec`
+    pub treat_whitespace_as_suffix: bool,
+    /// Keeps `\t`, `\n` and `\r` as they are, instead of regarding them as whitespace. The
+    /// `precompiled_charsmap` of `Rule::Precompiled` may still map them for upstream
+    /// SentencePiece.
+    pub preserve_whitespace: bool,
+}
+
+/// Same as the defaults of `TrainSpec`.
+impl Default for Normalizer {
+    fn default() -> Self {
+        Self {
+            keep_extra_whitespaces: false,
+            rule: Rule::default(),
+            byte_level: false,
+            add_dummy_prefix: true,
+            escape_whitespaces: true,
+            treat_whitespace_as_suffix: false,
+            preserve_whitespace: false,
+        }
+    }
+}
+
+impl Normalizer {
+    /// Fails if `spec.normalization_rule_name` is unknown, or `spec.normalizer_model` or
+    /// `spec.normalization_rule_tsv` cannot be loaded.
+    pub fn new(spec: &TrainSpec) -> Result<Self> {
+        let rule = match &spec.normalizer_model {
+            Some(path) => Self::from_spec(ModelProto::load(path)?.get_normalizer_spec())?.rule,
+            None => match Rule::from_name(&spec.normalization_rule_name) {
+                Some(rule) => rule,
+                None => {
+                    return_err!(
+                        "unknown normalization_rule_name: {:?}",
+                        spec.normalization_rule_name
+                    );
+                }
+            },
+        };
+        let rule = match &spec.normalization_rule_tsv {
+            Some(path) => {
+                let tsv = fs::read_to_string(path).map_err(|e| anyhow!("{}: {:?}", e, path))?;
+                rule.with_user_rules(parse_rule_tsv(&tsv)?)?
+            }
+            None => rule,
+        };
+        Ok(Self {
+            keep_extra_whitespaces: spec.keep_extra_whitespaces || spec.preserve_whitespace,
+            rule,
+            byte_level: spec.byte_level,
+            add_dummy_prefix: spec.add_dummy_prefix,
+            escape_whitespaces: spec.escape_whitespaces,
+            treat_whitespace_as_suffix: spec.treat_whitespace_as_suffix,
+            preserve_whitespace: spec.preserve_whitespace,
+        })
+    }
+
+    /// Restores the normalizer from the spec stored in a model, except for
+    /// `treat_whitespace_as_suffix` in `TrainerSpec`.
+    ///
+    /// As upstream SentencePiece, `precompiled_charsmap` is used if any, even if the name is one
+    /// of the Unicode normalization forms, since models of upstream SentencePiece may have the
+    /// same names for different rules. The names mean the rules of this crate only if the
+    /// charsmap is generated by this crate from them (see `to_spec`) or there is none, and the
+    /// others mean identity.
+    pub fn from_spec(spec: &NormalizerSpec) -> Result<Self> {
+        let rule = match Rule::from_name(spec.get_name()) {
+            Some(rule) if spec.get_generated_charsmap() => rule,
+            _ if !spec.get_precompiled_charsmap().is_empty() => Rule::Precompiled {
+                name: spec.get_name().to_string(),
+                charsmap: CharsMap::from_blob(spec.get_precompiled_charsmap())?,
+            },
+            Some(rule) => rule,
+            None => Rule::Identity,
+        };
+        Ok(Self {
+            keep_extra_whitespaces: !spec.get_remove_extra_whitespaces(),
+            rule,
+            byte_level: spec.get_byte_level(),
+            add_dummy_prefix: spec.get_add_dummy_prefix(),
+            escape_whitespaces: spec.get_escape_whitespaces(),
+            treat_whitespace_as_suffix: false,
+            preserve_whitespace: spec.get_preserve_whitespace(),
+        })
+    }
+
+    /// The spec stored in models. The Unicode normalization forms are also written as
+    /// `precompiled_charsmap`, marked as generated so that `from_spec` restores the form.
+    pub fn to_spec(&self) -> NormalizerSpec {
+        let mut spec = NormalizerSpec::new();
+        spec.set_name(self.rule.name().to_string());
+        match &self.rule {
+            Rule::Identity => {}
+            Rule::Precompiled { charsmap, .. } => {
+                spec.set_precompiled_charsmap(charsmap.to_blob());
+            }
+            rule => {
+                // for upstream SentencePiece, which normalizes only with the charsmap
+                spec.set_precompiled_charsmap(generated_charsmap(rule, self.preserve_whitespace));
+                spec.set_generated_charsmap(true);
+            }
+        }
+        spec.set_add_dummy_prefix(self.add_dummy_prefix);
+        spec.set_remove_extra_whitespaces(!self.keep_extra_whitespaces);
+        spec.set_escape_whitespaces(self.escape_whitespaces);
+        spec.set_byte_level(self.byte_level);
+        spec.set_preserve_whitespace(self.preserve_whitespace);
+        spec
+    }
+
+    /// The char which whitespace is replaced with. See `space_char`.
+    pub fn space_char(&self) -> char {
+        space_char(self.byte_level, self.escape_whitespaces)
+    }
+
+    /// Applies `self.rule` only.
+    pub fn normalize(&self, s: &str) -> String {
+        match &self.rule {
+            Rule::Identity => s.to_string(),
+            _ => self
+                .normalize_with_offsets(s)
+                .into_iter()
+                .map(|(c, _)| c)
+                .collect(),
+        }
+    }
+
+    /// Same as `normalize`, with the byte offset in `s` of the char each char is made from.
+    pub fn normalize_with_offsets(&self, s: &str) -> Vec<(char, usize)> {
+        match &self.rule {
+            Rule::Identity => s.char_indices().map(|(i, c)| (c, i)).collect(),
+            Rule::Precompiled { charsmap, .. } => {
+                apply_charsmap(charsmap, s, self.preserve_whitespace)
+            }
+            rule => {
+                let mut ret = Vec::with_capacity(s.len());
+                let mut normalized = String::new();
+                for (offset, run) in rule.runs(s) {
+                    let start = ret.len();
+                    for (i, c) in run.char_indices() {
+                        normalized.clear();
+                        rule.push_normalized(c.encode_utf8(&mut [0; 4]), &mut normalized);
+                        ret.extend(normalized.chars().map(|c| (c, offset + i)));
+                    }
+                    if run.chars().nth(1).is_none() {
+                        continue;
+                    }
+                    // chars composed or reordered in the run are made from the whole run
+                    normalized.clear();
+                    rule.push_normalized(run, &mut normalized);
+                    if !normalized.chars().eq(ret[start..].iter().map(|&(c, _)| c)) {
+                        ret.truncate(start);
+                        ret.extend(normalized.chars().map(|c| (c, offset)));
+                    }
+                }
+                ret
+            }
+        }
+    }
+
+    /// Upstream SentencePiece regards only U+0020 as whitespace after normalization.
+    /// `unicode_rules` maps the other whitespace to U+0020, so that the Unicode normalization
+    /// forms are consistent with it.
+    fn is_whitespace(&self, c: char) -> bool {
+        if self.preserve_whitespace && is_preserved_whitespace(c) {
+            false
+        } else if self.rule.is_unicode_form() {
+            c.is_whitespace()
         } else {
-            ret.push(c);
-            is_prev_space = false;
+            c == ' '
+        }
+    }
+
+    /// 1. normalize with `self.rule`
+    /// 2. replace whitespace to U+2581 unless `!escape_whitespaces`, and add it to the beginning,
+    ///    or to the end with `treat_whitespace_as_suffix`, if `add_dummy_prefix`
+    /// 3. in byte-level mode, map the UTF-8 bytes to chars, with U+2581 as a space
+    ///
+    /// Returns empty if `s` has no chars to be encoded.
+    pub fn to_chars(&self, s: &str) -> Vec<char> {
+        self.to_chars_with_offsets(s).0
+    }
+
+    /// Same as `to_chars`, with the alignment to `s` as `norm_to_orig` of upstream SentencePiece:
+    /// the byte offset in `s` of each char, followed by `s.len()`, so that chars `i..j` are made
+    /// from `s[offsets[i]..offsets[j]]`.
+    ///
+    /// Chars made from the same char of `s` have the same offset. Whitespace and chars removed by
+    /// normalization belong to the preceding char, e.g. the leading ones to the dummy prefix. The
+    /// dummy suffix has the offset `s.len()`.
+    pub fn to_chars_with_offsets(&self, s: &str) -> (Vec<char>, Vec<usize>) {
+        let space = space_char(false, self.escape_whitespaces);
+        let prefix = self.add_dummy_prefix && !self.treat_whitespace_as_suffix;
+        let mut ret = vec![];
+        let mut offsets = vec![];
+        if prefix {
+            ret.push(space);
+            offsets.push(0);
+        }
+        let mut is_prev_space = !self.keep_extra_whitespaces;
+
+        for (c, offset) in self.normalize_with_offsets(s) {
+            if self.is_whitespace(c) {
+                if !is_prev_space {
+                    ret.push(space);
+                    offsets.push(offset);
+                    is_prev_space = !self.keep_extra_whitespaces;
+                }
+            } else {
+                ret.push(c);
+                offsets.push(offset);
+                is_prev_space = false;
+            }
+        }
+        let dummy = prefix as usize;
+        if !self.keep_extra_whitespaces && ret.len() > dummy && ret.last() == Some(&space) {
+            ret.pop();
+            offsets.pop();
+        }
+        if ret.len() == dummy {
+            ret.clear();
+            offsets.clear();
+        } else if self.add_dummy_prefix && self.treat_whitespace_as_suffix {
+            ret.push(space);
+            offsets.push(s.len());
+        }
+        if self.byte_level {
+            let mut bytes = Vec::with_capacity(ret.len());
+            let mut byte_offsets = Vec::with_capacity(ret.len());
+            let mut buf = [0; 4];
+            for (c, offset) in ret.into_iter().zip(offsets) {
+                let c = if c == SPACE_REP { ' ' } else { c };
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    bytes.push(byte_to_char(b));
+                    byte_offsets.push(offset);
+                }
+            }
+            ret = bytes;
+            offsets = byte_offsets;
+        }
+        offsets.push(s.len());
+        (ret, offsets)
+    }
+}
+
+/// Replaces the longest matches of the rules in `charsmap` from the beginning of `s`. Chars
+/// have the byte offset in `s` of the replaced match. With `preserve_whitespace`, `\t`, `\n`
+/// and `\r` are never replaced.
+fn apply_charsmap(charsmap: &CharsMap, s: &str, preserve_whitespace: bool) -> Vec<(char, usize)> {
+    let mut ret = Vec::with_capacity(s.len());
+    let mut offset = 0;
+    while let Some(c) = s[offset..].chars().next() {
+        let matched = if preserve_whitespace && is_preserved_whitespace(c) {
+            None
+        } else {
+            charsmap.normalize_prefix(&s[offset..])
+        };
+        match matched {
+            Some((len, normalized)) => {
+                ret.extend(normalized.chars().map(|c| (c, offset)));
+                offset += len;
+            }
+            None => {
+                ret.push((c, offset));
+                offset += c.len_utf8();
+            }
         }
     }
     ret
 }
 
+/// Parses rules in the format of `normalization_rule_tsv` of upstream SentencePiece.
+///
+/// Each line is `source\ttarget`, where both are code points in hex separated by spaces, e.g.
+/// `FF5E\t7E`. The target may be empty to remove the source. Further columns, empty lines and
+/// lines starting with `#` are ignored.
+pub fn parse_rule_tsv(tsv: &str) -> Result<BTreeMap<String, String>> {
+    let mut rules = BTreeMap::new();
+    for (i, line) in tsv.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let (src, tgt) = match (fields.next(), fields.next()) {
+            (Some(src), Some(tgt)) => (src, tgt),
+            _ => {
+                return_err!("no target at line {}: {:?}", i + 1, line);
+            }
+        };
+        let parse = |s: &str| -> Option<String> {
+            s.split_whitespace()
+                .map(|h| {
+                    u32::from_str_radix(h, 16)
+                        .ok()
+                        .and_then(std::char::from_u32)
+                })
+                .collect()
+        };
+        match (parse(src), parse(tgt)) {
+            (Some(src), Some(tgt)) if !src.is_empty() => {
+                if rules.insert(src, tgt).is_some() {
+                    return_err!("duplicated source at line {}: {:?}", i + 1, line);
+                }
+            }
+            _ => {
+                return_err!("invalid rule at line {}: {:?}", i + 1, line);
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// `precompiled_charsmap` of `unicode_rules`, built once per rule since it takes a while.
+fn generated_charsmap(rule: &Rule, preserve_whitespace: bool) -> Vec<u8> {
+    // blobs by the names of rules and `preserve_whitespace`
+    type Blobs = HashMap<(String, bool), Vec<u8>>;
+    static CHARSMAPS: Lazy<Mutex<Blobs>> = Lazy::new(Mutex::default);
+    let key = (rule.name().to_string(), preserve_whitespace);
+    if let Some(blob) = CHARSMAPS.lock().unwrap().get(&key) {
+        return blob.clone();
+    }
+    let blob = CharsMap::build(&unicode_rules(rule, preserve_whitespace)).to_blob();
+    CHARSMAPS.lock().unwrap().insert(key, blob.clone());
+    blob
+}
+
+/// Rules approximating a Unicode normalization form, optionally with `preserve_whitespace`:
+/// - the rules of single chars,
+/// - the rules of the canonical decompositions of chars which are composed differently from
+///   their chars normalized one by one, e.g. `e\u{301}` to `é` in NFC,
+/// - the rules of two combining marks out of the canonical order, e.g. `\u{301}\u{323}` to
+///   `\u{323}\u{301}`,
+/// - and the rules of a char followed by a combining mark which is ordered before the marks of
+///   the char or composes with it, e.g. `é\u{323}` to `e\u{323}\u{301}` in NFKD, or `ạ\u{302}`
+///   to `ậ` in NFC.
+///
+/// Only runs of a starter and combining marks with more than one mark out of the canonical
+/// order are normalized differently from the form.
+fn unicode_rules(rule: &Rule, preserve_whitespace: bool) -> BTreeMap<String, String> {
+    let normalize = |s: &str| {
+        let mut normalized = String::new();
+        rule.push_normalized(s, &mut normalized);
+        normalized
+    };
+    let mut rules = BTreeMap::new();
+    for &c in &UNICODE_TABLES.changed {
+        let src = c.to_string();
+        let tgt = if c.is_whitespace() && !(preserve_whitespace && is_preserved_whitespace(c)) {
+            " ".to_string()
+        } else {
+            normalize(&src)
+        };
+        if tgt != src {
+            rules.insert(src, tgt);
+        }
+    }
+    for &c in &UNICODE_TABLES.changed {
+        let src: String = iter::once(c).nfd().collect();
+        if src.chars().nth(1).is_none() {
+            continue;
+        }
+        let per_char: String = src
+            .chars()
+            .map(|c| {
+                rules
+                    .get(&c.to_string())
+                    .cloned()
+                    .unwrap_or_else(|| c.to_string())
+            })
+            .collect();
+        let tgt = normalize(&src);
+        if tgt != per_char {
+            rules.insert(src, tgt);
+        }
+    }
+    let mut reordered = BTreeMap::new();
+    for src in reordered_sources(rule, &rules) {
+        let tgt = normalize(&src);
+        if tgt != apply_rules(&rules, &src) {
+            reordered.insert(src, tgt);
+        }
+    }
+    rules.extend(reordered);
+    rules
+}
+
+/// Sources of the rules which reorder combining marks or compose them with a preceding
+/// precomposed char. See `unicode_rules`.
+fn reordered_sources(rule: &Rule, rules: &BTreeMap<String, String>) -> Vec<String> {
+    let tables = &*UNICODE_TABLES;
+    let ccc = canonical_combining_class;
+    let mut sources = vec![];
+    for &a in &tables.marks {
+        for &b in tables.marks.iter().filter(|&&b| ccc(b) < ccc(a)) {
+            sources.push([a, b].iter().collect());
+        }
+    }
+    let compatible = !matches!(rule, Rule::Nfc);
+    for &c in &tables.changed {
+        let canonical: String = iter::once(c).nfd().collect();
+        let last = if compatible {
+            iter::once(c).nfkd().last()
+        } else {
+            canonical.chars().last()
+        };
+        let last_ccc = last.map_or(0, ccc);
+        for &m in tables.marks.iter().filter(|&&m| ccc(m) < last_ccc) {
+            sources.push(format!("{}{}", c, m));
+            if rules.contains_key(&canonical) {
+                sources.push(format!("{}{}", canonical, m));
+            }
+        }
+    }
+    if !matches!(rule, Rule::Nfkd) {
+        // `c` + `m` where `c` and `m` are the decomposition of a precomposed char
+        for decomposed in tables.decompositions.keys() {
+            let chars: Vec<_> = decomposed.chars().collect();
+            for i in 1..chars.len() {
+                let mut rest = chars.clone();
+                let m = rest.remove(i);
+                let rest: String = rest.into_iter().collect();
+                for c in tables.decompositions.get(&rest).into_iter().flatten() {
+                    sources.push(format!("{}{}", c, m));
+                }
+            }
+        }
+    }
+    sources
+}
+
+/// Replaces the longest matches of `rules` from the beginning of `s`, as `apply_charsmap`.
+fn apply_rules(rules: &BTreeMap<String, String>, s: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        let mut ends: Vec<_> = rest.char_indices().skip(1).map(|(i, _)| i).collect();
+        ends.push(rest.len());
+        let matched = ends
+            .into_iter()
+            .rev()
+            .find_map(|len| rules.get(&rest[..len]).map(|tgt| (len, tgt)));
+        match matched {
+            Some((len, tgt)) => {
+                ret.push_str(tgt);
+                rest = &rest[len..];
+            }
+            None => {
+                ret.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    ret
+}
+
+/// Rules of the full case folding of Unicode, which are not in the normalizers of Hugging Face
+/// `tokenizers`.
+pub fn case_folding_rules() -> BTreeMap<String, String> {
+    UNICODE_TABLES
+        .changed
+        .iter()
+        .filter_map(|&c| {
+            let folded: String = iter::once(c).default_case_fold().collect();
+            if folded.chars().eq(iter::once(c)) {
+                None
+            } else {
+                Some((c.to_string(), folded))
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
     #[test]
     fn test_to_chars() {
         let mut spec = TrainSpec::default();
         spec.keep_extra_whitespaces = false;
         let s = "  ab \t c\td  ";
         assert_eq!(
-            to_chars(s, &spec),
+            Normalizer::new(&spec).unwrap().to_chars(s),
             vec![SPACE_REP, 'a', 'b', SPACE_REP, 'c', SPACE_REP, 'd']
         );
+        assert!(Normalizer::new(&spec).unwrap().to_chars(" \t ").is_empty());
+    }
+
+    #[test]
+    fn test_whitespace_options() {
+        let to_chars = |normalizer: &Normalizer, s: &str| -> String {
+            normalizer.to_chars(s).into_iter().collect()
+        };
+        let mut normalizer = Normalizer::default();
+        let s = " ab  c ";
+        assert_eq!(to_chars(&normalizer, s), "▁ab▁c");
+        normalizer.treat_whitespace_as_suffix = true;
+        assert_eq!(to_chars(&normalizer, s), "ab▁c▁");
+        assert_eq!(
+            normalizer.to_chars_with_offsets(s).1,
+            vec![1, 2, 3, 5, 7, 7]
+        );
+        normalizer.add_dummy_prefix = false;
+        assert_eq!(to_chars(&normalizer, s), "ab▁c");
+        normalizer.treat_whitespace_as_suffix = false;
+        assert_eq!(to_chars(&normalizer, s), "ab▁c");
+        normalizer.escape_whitespaces = false;
+        assert_eq!(to_chars(&normalizer, s), "ab c");
+        assert_eq!(normalizer.space_char(), ' ');
+        normalizer.add_dummy_prefix = true;
+        assert_eq!(to_chars(&normalizer, s), " ab c");
+        assert!(normalizer.to_chars("  ").is_empty());
+
+        let spec = normalizer.to_spec();
+        assert!(!spec.get_escape_whitespaces());
+        let restored = Normalizer::from_spec(&spec).unwrap();
+        assert_eq!(to_chars(&restored, s), " ab c");
+    }
+
+    #[test]
+    fn test_preserve_whitespace() {
+        let normalizer = Normalizer {
+            preserve_whitespace: true,
+            keep_extra_whitespaces: true,
+            ..Normalizer::default()
+        };
+        let s = "\tx\n  y\r\n";
+        let chars: String = normalizer.to_chars(s).into_iter().collect();
+        assert_eq!(chars, "▁\tx\n▁▁y\r\n");
+        let normalized: String = Normalizer::default().to_chars(s).into_iter().collect();
+        assert_eq!(normalized, "▁x▁y");
+
+        let mut spec = normalizer.to_spec();
+        assert!(spec.get_preserve_whitespace());
+        spec.set_name("nfkd_precompiled".into());
+        let mut precompiled = Normalizer::from_spec(&spec).unwrap();
+        assert!(precompiled.preserve_whitespace);
+        assert_eq!(precompiled.to_chars(s), normalizer.to_chars(s));
+        // upstream SentencePiece, which has no `preserve_whitespace`, keeps them as well
+        precompiled.preserve_whitespace = false;
+        assert_eq!(precompiled.to_chars(s), normalizer.to_chars(s));
+    }
+
+    #[test]
+    fn test_to_chars_with_offsets() {
+        let normalizer = Normalizer::default();
+        let s = "  ab \t ﬁé ";
+        let (chars, offsets) = normalizer.to_chars_with_offsets(s);
+        assert_eq!(chars, normalizer.to_chars(s));
+        assert_eq!(chars.iter().collect::<String>(), "▁ab▁fie\u{301}");
+        assert_eq!(offsets, vec![0, 2, 3, 4, 7, 7, 10, 10, 13]);
+        let surfaces: Vec<_> = offsets.windows(2).map(|w| &s[w[0]..w[1]]).collect();
+        assert_eq!(surfaces, vec!["  ", "a", "b", " \t ", "", "ﬁ", "", "é "]);
+
+        let normalizer = Normalizer {
+            byte_level: true,
+            keep_extra_whitespaces: true,
+            ..Normalizer::default()
+        };
+        let (chars, offsets) = normalizer.to_chars_with_offsets("aé ");
+        assert_eq!(chars.len(), 6);
+        assert_eq!(offsets, vec![0, 0, 1, 1, 1, 3, 4]);
+        assert_eq!(
+            Normalizer::default().to_chars_with_offsets(" "),
+            (vec![], vec![1])
+        );
+    }
+
+    #[test]
+    fn test_precompiled_nfkd() {
+        let nfkd = Normalizer::default();
+        let precompiled = Normalizer::from_spec(&{
+            let mut spec = nfkd.to_spec();
+            spec.set_name("nfkd_precompiled".into());
+            spec
+        })
+        .unwrap();
+        assert!(matches!(precompiled.rule, Rule::Precompiled { .. }));
+        for s in &["ｶﾞｷﾞ　①ｱ", " é\u{a0}ﬁ\u{2003}x ", "가나다¨"] {
+            assert_eq!(nfkd.to_chars(s), precompiled.to_chars(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_unicode_forms() {
+        let s = "Ｃafé e\u{301}\u{2003}ﬁ";
+        for (rule, expected) in &[
+            (Rule::Nfkd, "▁Cafe\u{301}▁e\u{301}▁fi"),
+            (Rule::Nfkc, "▁Café▁é▁fi"),
+            (Rule::Nfc, "▁Ｃafé▁é▁ﬁ"),
+            (Rule::NfkcCf, "▁café▁é▁fi"),
+            (Rule::Identity, "▁Ｃafé▁e\u{301}\u{2003}ﬁ"),
+        ] {
+            let normalizer = Normalizer {
+                rule: rule.clone(),
+                ..Normalizer::default()
+            };
+            let chars: String = normalizer.to_chars(s).into_iter().collect();
+            assert_eq!(&chars, expected, "{:?}", rule);
+            assert_eq!(Rule::from_name(rule.name()).as_ref(), Some(rule));
+        }
+
+        let normalizer = |rule: Rule| Normalizer {
+            rule,
+            keep_extra_whitespaces: true,
+            add_dummy_prefix: false,
+            ..Normalizer::default()
+        };
+        // composed or reordered chars have the offset of the run
+        let s = "e\u{301}\u{1100}\u{1161}\u{11a8}a\u{301}\u{316}";
+        assert_eq!(
+            normalizer(Rule::Nfc).normalize_with_offsets(s),
+            vec![('é', 0), ('각', 3), ('á', 12), ('\u{316}', 12)]
+        );
+        assert_eq!(
+            normalizer(Rule::Nfkd).normalize_with_offsets("a\u{301}\u{316}b\u{301}"),
+            vec![
+                ('a', 0),
+                ('\u{316}', 0),
+                ('\u{301}', 0),
+                ('b', 5),
+                ('\u{301}', 6)
+            ]
+        );
+        // full case folding, unlike lowercasing
+        assert_eq!(
+            normalizer(Rule::NfkcCf).normalize("Straße ΣΑΣ ǅ"),
+            "strasse σασ dž"
+        );
+
+        let nfkc_cf = Normalizer {
+            rule: Rule::NfkcCf,
+            ..Normalizer::default()
+        };
+        let mut spec = nfkc_cf.to_spec();
+        assert_eq!(spec.get_name(), NFKC_CF);
+        assert!(spec.get_generated_charsmap());
+        assert_eq!(Normalizer::from_spec(&spec).unwrap().rule, Rule::NfkcCf);
+        spec.set_generated_charsmap(false);
+        let precompiled = Normalizer::from_spec(&spec).unwrap();
+        assert_eq!(precompiled.rule.name(), NFKC_CF);
+        assert!(matches!(precompiled.rule, Rule::Precompiled { .. }));
+        for s in &[
+            s,
+            "Ｃafé e\u{301}\u{2003}ﬁ",
+            "Straße ΣΑΣ",
+            "\u{1100}\u{1161}\u{11a8} \u{1100}\u{1161}",
+        ] {
+            assert_eq!(nfkc_cf.to_chars(s), precompiled.to_chars(s), "{:?}", s);
+        }
+        let mut spec = Normalizer::default().to_spec();
+        spec.clear_precompiled_charsmap();
+        assert_eq!(Normalizer::from_spec(&spec).unwrap().rule, Rule::Nfkd);
+    }
+
+    #[test]
+    fn test_precompiled_combining_marks() {
+        // two marks out of the canonical order, a precomposed char followed by a mark ordered
+        // before its own, and a precomposed char composing with a following mark
+        let stacked = [
+            "a\u{301}\u{323}",
+            "Vie\u{302}\u{323}t",
+            "é\u{323}",
+            "ế\u{323}",
+            "a\u{301}\u{323}\u{302}",
+            "â\u{323} ạ\u{302}",
+            "\u{5db}\u{5bf}\u{5b4}",
+        ];
+        for rule in &[Rule::Nfkd, Rule::Nfkc, Rule::Nfc, Rule::NfkcCf] {
+            let normalizer = Normalizer {
+                rule: rule.clone(),
+                ..Normalizer::default()
+            };
+            let mut spec = normalizer.to_spec();
+            spec.set_generated_charsmap(false);
+            let precompiled = Normalizer::from_spec(&spec).unwrap();
+            assert!(matches!(precompiled.rule, Rule::Precompiled { .. }));
+            for s in &stacked {
+                assert_eq!(
+                    normalizer.normalize(s),
+                    precompiled.normalize(s),
+                    "{:?} {:?}",
+                    rule,
+                    s
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rule_tsv() {
+        let tsv = "# strip acute accents\n301\t\n3002\t2E\n3001\t2C\t# ideographic comma\n\n";
+        let rules = parse_rule_tsv(tsv).unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules["\u{3002}"], ".");
+        assert_eq!(rules["\u{301}"], "");
+        for tsv in &[
+            "41\n",
+            "41\tXYZ\n",
+            "\t41\n",
+            "41\t42\n41\t43\n",
+            "110000\t41\n",
+        ] {
+            assert!(parse_rule_tsv(tsv).is_err(), "{:?}", tsv);
+        }
+
+        let normalizer = |rule: Rule| Normalizer {
+            rule: rule.with_user_rules(rules.clone()).unwrap(),
+            ..Normalizer::default()
+        };
+        let s = "Café e\u{301}。、";
+        let chars: String = normalizer(Rule::Nfkd).to_chars(s).into_iter().collect();
+        assert_eq!(chars, "▁Cafe▁e.,");
+        let chars: String = normalizer(Rule::Identity).to_chars(s).into_iter().collect();
+        assert_eq!(chars, "▁Café▁e.,");
+
+        let normalizer = normalizer(Rule::Nfkd);
+        let spec = normalizer.to_spec();
+        assert_eq!(spec.get_name(), USER_DEFINED);
+        let restored = Normalizer::from_spec(&spec).unwrap();
+        assert_eq!(restored.to_chars(s), normalizer.to_chars(s));
+        assert!(restored.rule.with_user_rules(rules).is_err());
+    }
+
+    #[test]
+    fn test_byte_level() {
+        let chars: Vec<_> = (0..=255).map(byte_to_char).collect();
+        assert_eq!(chars.iter().collect::<HashSet<_>>().len(), 256);
+        for (b, &c) in chars.iter().enumerate() {
+            assert_eq!(char_to_byte(c), Some(b as u8));
+        }
+        assert_eq!(byte_to_char(b' '), '\u{120}');
+        assert_eq!(byte_to_char(0xad), '\u{143}');
+        assert_eq!(char_to_byte(SPACE_REP), None);
+
+        let mut normalizer = Normalizer::default();
+        normalizer.byte_level = true;
+        assert_eq!(normalizer.space_char(), '\u{120}');
+        assert_eq!(
+            normalizer.to_chars("a  é"),
+            "\u{120}a\u{120}e\u{cc}\u{123}".chars().collect::<Vec<_>>()
+        );
+        let spec = normalizer.to_spec();
+        assert!(spec.get_byte_level());
+        assert!(Normalizer::from_spec(&spec).unwrap().byte_level);
+        assert!(!Normalizer::default().to_spec().get_byte_level());
     }
 }
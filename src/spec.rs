@@ -1,14 +1,249 @@
+use crate::input::InputFormat;
+use crate::protos::sentencepiece_model::{TrainerSpec, TrainerSpec_ModelType};
 use clap::Clap;
-#[derive(Clap, Debug, Default)]
+#[derive(Clap, Debug)]
 pub struct TrainSpec {
     #[clap(short, long, default_value = "8000")]
     pub vocab_size: usize,
     #[clap(short, long)]
     pub model_prefix: String,
-    pub input: String,
+    /// Input files, directories or glob patterns. Can be separated by commas.
+    #[clap(required_unless = "input-option")]
+    pub input: Vec<String>,
+    /// Same as `input`, as `spm_train --input`. Can be repeated.
+    #[clap(short = "i", long = "input", number_of_values = 1)]
+    pub input_option: Vec<String>,
+    /// Format of the input files: `text` (a sentence per line), `tsv` (`sentence\tcount` per
+    /// line) or `jsonl` (a JSON object per line). Files ending with `.gz` or `.zst` are
+    /// decompressed.
+    #[clap(long, default_value = "text", possible_values = &["text", "tsv", "jsonl"])]
+    pub input_format: InputFormat,
+    /// Dot-separated path of the sentence in `jsonl` records, e.g. `meta.text`. Not stored in the
+    /// model, since `TrainerSpec` has no field for it.
+    #[clap(long, default_value = "text")]
+    pub jsonl_field: String,
     #[clap(short, long)]
     pub keep_extra_whitespaces: bool,
+    /// Unicode normalization form: `nfkd`, `nfkc`, `nfc`, `nfkc_cf` (NFKC with case folding) or
+    /// `identity`.
+    #[clap(long, default_value = "nfkd")]
+    pub normalization_rule_name: String,
+    /// TSV of rules `source\ttarget` in hex code points, e.g. `FF5E\t7E`, applied after
+    /// `normalization_rule_name` with the longest match. Use `identity` to apply them alone.
+    #[clap(long)]
+    pub normalization_rule_tsv: Option<String>,
+    /// Model whose normalization rule is used instead of `normalization_rule_name`, e.g. an
+    /// upstream SentencePiece model with the `precompiled_charsmap` of `nmt_nfkc`.
+    #[clap(long)]
+    pub normalizer_model: Option<String>,
+    /// Fraction of chars in the corpus covered by the initial alphabet. The rest are trained as
+    /// `<unk>`. Upstream SentencePiece uses 0.9995 by default.
+    #[clap(long, default_value = "1.0")]
+    pub character_coverage: f32,
+    /// Adds whitespace at the beginning of each sentence, so that the first word is encoded in
+    /// the same way as the others.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub add_dummy_prefix: bool,
+    /// Replaces whitespace with U+2581. Otherwise it is kept as U+0020.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub escape_whitespaces: bool,
+    /// Whitespace is at the end of pieces instead of the beginning, and the dummy prefix is added
+    /// at the end of sentences.
+    #[clap(long)]
+    pub treat_whitespace_as_suffix: bool,
+    /// Keeps `\t`, `\n`, `\r` and runs of spaces as they are, and trains on whole files instead
+    /// of lines, e.g. for source code. Pieces of only whitespace, e.g. indentation, are allowed.
+    /// Implies `keep_extra_whitespaces`.
+    #[clap(long)]
+    pub preserve_whitespace: bool,
+    /// Pieces never span words. Otherwise they only never end with whitespace, or never start
+    /// with it with `treat_whitespace_as_suffix`. On by default, as in upstream SentencePiece.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub split_by_whitespace: bool,
+    /// Pieces never span Unicode scripts. Hiragana and Katakana are regarded as Han. On by
+    /// default, as in upstream SentencePiece.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub split_by_unicode_script: bool,
+    /// Pieces never span digits and other chars. On by default, as in upstream SentencePiece.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub split_by_number: bool,
+    /// Digits are always pieces of their own.
+    #[clap(long)]
+    pub split_digits: bool,
+    /// Maximum length of pieces in chars, including the whitespace char. 16 by default, as in
+    /// upstream SentencePiece.
+    #[clap(long, default_value = "16")]
+    pub max_sentencepiece_length: usize,
+    /// Trains on unique words weighted by their counts, with memory proportional to the number
+    /// of unique words rather than the corpus size. Requires `split_by_whitespace`, with which
+    /// the merges are the same as training on sentences. Without it, pieces may span words, so
+    /// there is no equivalent on words.
+    #[clap(long)]
+    pub word_frequency: bool,
+    /// Maximum number of sentences to train on. 0 means all.
+    #[clap(long, default_value = "0")]
+    pub input_sentence_size: usize,
+    /// Samples `input_sentence_size` sentences randomly from the whole input, instead of taking
+    /// the first ones.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub shuffle_input_sentence: bool,
+    /// Seed of sentence sampling. Not stored in the model, since `TrainerSpec` has no field for it.
+    #[clap(long, default_value = "0")]
+    pub random_seed: u64,
+    /// Threads for loading the input and counting pairs. The output does not depend on it, so it is
+    /// not stored in the model.
+    #[clap(long, default_value = "1")]
+    pub num_threads: usize,
+    /// Trains on UTF-8 bytes mapped to the 256 chars of GPT-2, so that any text is encoded
+    /// without `<unk>`. Requires `character_coverage` of 1.
+    #[clap(long)]
+    pub byte_level: bool,
+    /// Pieces only used by applications, e.g. `<sep>`, with reserved ids. Never produced by
+    /// encoding. Can be repeated or separated by commas.
+    #[clap(long, number_of_values = 1)]
+    pub control_symbols: Vec<String>,
+    /// Pieces which are always encoded as a whole, and never split or merged in training. Can be
+    /// repeated or separated by commas.
+    #[clap(long, number_of_values = 1)]
+    pub user_defined_symbols: Vec<String>,
+    /// Id of `unk_piece`. Required.
+    #[clap(long, default_value = "0", allow_hyphen_values = true)]
+    pub unk_id: i32,
+    /// Id of `bos_piece`, or -1 to disable it.
+    #[clap(long, default_value = "1", allow_hyphen_values = true)]
+    pub bos_id: i32,
+    /// Id of `eos_piece`, or -1 to disable it.
+    #[clap(long, default_value = "2", allow_hyphen_values = true)]
+    pub eos_id: i32,
+    /// Id of `pad_piece`, or -1 to disable it.
+    #[clap(long, default_value = "-1", allow_hyphen_values = true)]
+    pub pad_id: i32,
+    #[clap(long, default_value = "<unk>")]
+    pub unk_piece: String,
+    #[clap(long, default_value = "<s>")]
+    pub bos_piece: String,
+    #[clap(long, default_value = "</s>")]
+    pub eos_piece: String,
+    #[clap(long, default_value = "<pad>")]
+    pub pad_piece: String,
+    /// Reserves 256 pieces `<0x00>`..`<0xFF>`, to which chars out of the vocabulary are encoded
+    /// as UTF-8 bytes instead of `<unk>`.
+    #[clap(long)]
+    pub byte_fallback: bool,
     #[cfg(debug_assertions)]
     #[clap(long)]
     pub slow: bool,
 }
+
+/// Same as the defaults of the command line. The boundaries of pieces (`split_by_*` and
+/// `max_sentencepiece_length`) default to those of upstream `spm_train`.
+impl Default for TrainSpec {
+    fn default() -> Self {
+        Self {
+            vocab_size: 8000,
+            model_prefix: String::new(),
+            input: vec![],
+            input_option: vec![],
+            input_format: InputFormat::Text,
+            jsonl_field: "text".to_string(),
+            keep_extra_whitespaces: false,
+            normalization_rule_name: crate::norm::NFKD.to_string(),
+            normalizer_model: None,
+            normalization_rule_tsv: None,
+            character_coverage: 1.0,
+            add_dummy_prefix: true,
+            escape_whitespaces: true,
+            treat_whitespace_as_suffix: false,
+            preserve_whitespace: false,
+            split_by_whitespace: true,
+            split_by_unicode_script: true,
+            split_by_number: true,
+            split_digits: false,
+            max_sentencepiece_length: 16,
+            word_frequency: false,
+            input_sentence_size: 0,
+            shuffle_input_sentence: true,
+            random_seed: 0,
+            num_threads: 1,
+            byte_level: false,
+            byte_fallback: false,
+            unk_id: 0,
+            bos_id: 1,
+            eos_id: 2,
+            pad_id: -1,
+            unk_piece: "<unk>".to_string(),
+            bos_piece: "<s>".to_string(),
+            eos_piece: "</s>".to_string(),
+            pad_piece: "<pad>".to_string(),
+            control_symbols: vec![],
+            user_defined_symbols: vec![],
+            #[cfg(debug_assertions)]
+            slow: false,
+        }
+    }
+}
+
+impl TrainSpec {
+    /// `input` followed by `input_option`
+    pub fn inputs(&self) -> Vec<String> {
+        self.input
+            .iter()
+            .chain(&self.input_option)
+            .cloned()
+            .collect()
+    }
+
+    /// `TrainerSpec` to be stored in the model.
+    pub fn to_trainer_spec(&self) -> TrainerSpec {
+        let mut spec = TrainerSpec::new();
+        spec.set_input(self.inputs().into());
+        spec.set_input_format(self.input_format.name().to_string());
+        spec.set_model_prefix(self.model_prefix.clone());
+        spec.set_model_type(TrainerSpec_ModelType::BPE);
+        spec.set_vocab_size(self.vocab_size as i32);
+        spec.set_character_coverage(self.character_coverage);
+        spec.set_split_by_whitespace(self.split_by_whitespace);
+        spec.set_treat_whitespace_as_suffix(self.treat_whitespace_as_suffix);
+        spec.set_split_by_unicode_script(self.split_by_unicode_script);
+        spec.set_split_by_number(self.split_by_number);
+        spec.set_split_digits(self.split_digits);
+        spec.set_max_sentencepiece_length(self.max_sentencepiece_length as i32);
+        spec.set_input_sentence_size(self.input_sentence_size as i32);
+        spec.set_shuffle_input_sentence(self.shuffle_input_sentence);
+        spec.set_byte_fallback(self.byte_fallback);
+        spec.set_unk_id(self.unk_id);
+        spec.set_bos_id(self.bos_id);
+        spec.set_eos_id(self.eos_id);
+        spec.set_pad_id(self.pad_id);
+        spec.set_unk_piece(self.unk_piece.clone());
+        spec.set_bos_piece(self.bos_piece.clone());
+        spec.set_eos_piece(self.eos_piece.clone());
+        spec.set_pad_piece(self.pad_piece.clone());
+        spec.set_control_symbols(split_commas(&self.control_symbols).into());
+        spec.set_user_defined_symbols(split_commas(&self.user_defined_symbols).into());
+        spec
+    }
+}
+
+fn split_commas(values: &[String]) -> Vec<String> {
+    values
+        .iter()
+        .flat_map(|s| s.split(','))
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inputs() {
+        let spec = TrainSpec::try_parse_from(["train", "a.txt", "b,c", "-m", "x", "-i", "d"]);
+        assert_eq!(spec.unwrap().inputs(), vec!["a.txt", "b,c", "d"]);
+        let spec = TrainSpec::try_parse_from(["train", "-i", "a", "--input", "b", "-m", "x"]);
+        assert_eq!(spec.unwrap().inputs(), vec!["a", "b"]);
+        assert!(TrainSpec::try_parse_from(["train", "-m", "x"]).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Boundaries which pieces never span: `split_by_unicode_script`, `split_by_number` and
+//! `split_digits` of `TrainerSpec`, and the pre-tokenization of GPT-2.
+use crate::norm;
+use crate::protos::sentencepiece_model::TrainerSpec;
+use crate::spec::TrainSpec;
+use crate::util;
+use protobuf::Message;
+use std::ops::Range;
+use unicode_script::{Script, UnicodeScript};
+
+/// Field number of the flag in `TrainerSpec`, stored as the extension
+///
+/// ```proto
+/// extend TrainerSpec {
+///   optional bool split_by_gpt2_pattern = 200;
+/// }
+/// ```
+const SPLIT_BY_GPT2_PATTERN_FIELD: u32 = 200;
+
+impl TrainerSpec {
+    /// Whether text is split into the pre-tokens of GPT-2 before merges. See `gpt2_pre_tokens`.
+    pub fn get_split_by_gpt2_pattern(&self) -> bool {
+        util::get_bool_extension(self.get_unknown_fields(), SPLIT_BY_GPT2_PATTERN_FIELD)
+    }
+
+    pub fn set_split_by_gpt2_pattern(&mut self, v: bool) {
+        util::set_bool_extension(self.mut_unknown_fields(), SPLIT_BY_GPT2_PATTERN_FIELD, v)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Boundaries {
+    pub by_unicode_script: bool,
+    pub by_number: bool,
+    pub digits: bool,
+    /// Pieces never span the pre-tokens of GPT-2
+    pub gpt2_pattern: bool,
+    /// Chars are bytes mapped by `norm::byte_to_char`
+    pub byte_level: bool,
+}
+
+impl Boundaries {
+    pub fn new(spec: &TrainSpec) -> Self {
+        Self {
+            by_unicode_script: spec.split_by_unicode_script,
+            by_number: spec.split_by_number,
+            digits: spec.split_digits,
+            gpt2_pattern: false,
+            byte_level: spec.byte_level,
+        }
+    }
+
+    pub fn from_spec(spec: &TrainerSpec, byte_level: bool) -> Self {
+        Self {
+            by_unicode_script: spec.get_split_by_unicode_script(),
+            by_number: spec.get_split_by_number(),
+            digits: spec.get_split_digits(),
+            gpt2_pattern: spec.get_split_by_gpt2_pattern(),
+            byte_level,
+        }
+    }
+
+    /// Whether `piece` lies within the boundaries. `space` is ignored, since whether pieces can
+    /// contain it is up to `split_by_whitespace`.
+    ///
+    /// As in upstream SentencePiece, Hiragana and Katakana are regarded as Han, and combining
+    /// marks as any script. Digits are of any script, and with `split_by_number`, never next to
+    /// other chars. With `split_digits`, digits are never in a piece of more than one char. In
+    /// byte-level mode, only ASCII bytes have scripts.
+    pub fn allows(&self, piece: &[char], space: char) -> bool {
+        let mut prev_script = None;
+        let mut prev_digit = None;
+        for &c in piece {
+            if c == space {
+                continue;
+            }
+            let c = if self.byte_level {
+                match norm::char_to_byte(c) {
+                    Some(b) if b.is_ascii() => b as char,
+                    _ => {
+                        prev_digit = Some(false);
+                        continue;
+                    }
+                }
+            } else {
+                c
+            };
+            let digit = is_digit(c);
+            if digit && self.digits && piece.len() > 1 {
+                return false;
+            }
+            if self.by_number && prev_digit.is_some_and(|d| d != digit) {
+                return false;
+            }
+            prev_digit = Some(digit);
+            if !self.by_unicode_script || digit {
+                continue;
+            }
+            let script = match c.script() {
+                Script::Inherited => continue,
+                Script::Hiragana | Script::Katakana => Script::Han,
+                // Katakana-Hiragana prolonged sound mark
+                _ if c == '\u{30fc}' => Script::Han,
+                s => s,
+            };
+            if prev_script.is_some_and(|s| s != script) {
+                return false;
+            }
+            prev_script = Some(script);
+        }
+        true
+    }
+
+    /// Ranges of `chars` to be encoded independently: the pre-tokens of GPT-2 with
+    /// `gpt2_pattern`, or the whole. Chars in byte-level mode are decoded as UTF-8 to find them.
+    pub fn pre_tokens(&self, chars: &[char]) -> Vec<Range<usize>> {
+        let whole = 0..chars.len();
+        if !self.gpt2_pattern {
+            return vec![whole];
+        }
+        if !self.byte_level {
+            return gpt2_pre_tokens(chars);
+        }
+        let text = chars
+            .iter()
+            .map(|&c| norm::char_to_byte(c))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let text: Vec<_> = match text {
+            Some(text) => text.chars().collect(),
+            None => return vec![whole],
+        };
+        // byte offset of each char
+        let mut offsets = vec![0];
+        for c in &text {
+            offsets.push(offsets[offsets.len() - 1] + c.len_utf8());
+        }
+        gpt2_pre_tokens(&text)
+            .into_iter()
+            .map(|r| offsets[r.start]..offsets[r.end])
+            .collect()
+    }
+}
+
+/// Splits `chars` as the pre-tokenization pattern of GPT-2
+///
+/// ```text
+/// 's|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+
+/// ```
+///
+/// with `char::is_alphabetic` as `\p{L}` and `char::is_numeric` as `\p{N}`.
+pub fn gpt2_pre_tokens(chars: &[char]) -> Vec<Range<usize>> {
+    #[derive(PartialEq)]
+    enum Class {
+        Letter,
+        Number,
+        Space,
+        Other,
+    }
+    let class = |c: char| {
+        if c.is_alphabetic() {
+            Class::Letter
+        } else if c.is_numeric() {
+            Class::Number
+        } else if c.is_whitespace() {
+            Class::Space
+        } else {
+            Class::Other
+        }
+    };
+    const CONTRACTIONS: [&str; 7] = ["'s", "'t", "'re", "'ve", "'m", "'ll", "'d"];
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let contraction = CONTRACTIONS
+            .iter()
+            .find(|s| s.chars().eq(chars[i..].iter().take(s.len()).copied()));
+        let end = if let Some(s) = contraction {
+            i + s.len()
+        } else {
+            // a space is followed by letters, numbers or others
+            let start = match chars.get(i + 1) {
+                Some(&c) if chars[i] == ' ' && class(c) != Class::Space => i + 1,
+                _ => i,
+            };
+            let cls = class(chars[start]);
+            let mut end = start + 1;
+            while end < chars.len() && class(chars[end]) == cls {
+                end += 1;
+            }
+            // whitespace but the last one before non-whitespace
+            if cls == Class::Space && end < chars.len() && end - start > 1 {
+                end -= 1;
+            }
+            end
+        };
+        ret.push(i..end);
+        i = end;
+    }
+    ret
+}
+
+/// ASCII and fullwidth digits, as upstream SentencePiece
+fn is_digit(c: char) -> bool {
+    matches!(c, '0'..='9' | '\u{ff10}'..='\u{ff19}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use norm::SPACE_REP;
+
+    fn allows(b: &Boundaries, s: &str) -> bool {
+        b.allows(&s.chars().collect::<Vec<_>>(), SPACE_REP)
+    }
+
+    #[test]
+    fn test_allows() {
+        let mut b = Boundaries::new(&TrainSpec::default());
+        for s in &["▁abc", "▁東京タワー", "ひらがな", "e\u{301}", "▁12", "１２"] {
+            assert!(allows(&b, s), "{:?}", s);
+        }
+        for s in &["ab東", "a,", "▁a1", "1a", "aа"] {
+            assert!(!allows(&b, s), "{:?}", s);
+        }
+
+        b.by_number = false;
+        assert!(allows(&b, "a1b"));
+        assert!(!allows(&b, "a1東"));
+        b.by_unicode_script = false;
+        assert!(allows(&b, "a1東,"));
+        b.digits = true;
+        assert!(allows(&b, "1"));
+        assert!(!allows(&b, "▁1"));
+        assert!(!allows(&b, "12"));
+
+        let mut b = Boundaries::new(&TrainSpec::default());
+        b.byte_level = true;
+        let bytes = |s: &str| norm::symbol_chars(s, true);
+        assert!(b.allows(&bytes(" abc"), norm::byte_to_char(b' ')));
+        assert!(b.allows(&bytes("東京"), norm::byte_to_char(b' ')));
+        assert!(!b.allows(&bytes("a1"), norm::byte_to_char(b' ')));
+        assert!(!b.allows(&bytes("a,"), norm::byte_to_char(b' ')));
+    }
+
+    #[test]
+    fn test_gpt2_pre_tokens() {
+        let s = "Hello world's  test 123!!\n\n  x I'm don't ";
+        let chars: Vec<_> = s.chars().collect();
+        let tokens: Vec<String> = gpt2_pre_tokens(&chars)
+            .into_iter()
+            .map(|r| chars[r].iter().collect())
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                "Hello", " world", "'s", " ", " test", " 123", "!!", "\n\n ", " x", " I", "'m",
+                " don", "'t", " "
+            ]
+        );
+
+        let b = Boundaries {
+            gpt2_pattern: true,
+            byte_level: true,
+            ..Boundaries::default()
+        };
+        let bytes = norm::symbol_chars("東京 2020", true);
+        assert_eq!(b.pre_tokens(&bytes), vec![0..6, 6..11]);
+        let mut spec = TrainerSpec::new();
+        assert!(!Boundaries::from_spec(&spec, true).gpt2_pattern);
+        spec.set_split_by_gpt2_pattern(true);
+        assert!(Boundaries::from_spec(&spec, true).gpt2_pattern);
+    }
+}
@@ -1,38 +1,59 @@
-use crate::norm;
+use crate::input::{self, InputFormat};
+use crate::model::Model;
+use crate::norm::{self, Normalizer};
 use crate::protos::sentencepiece_model::{
-    ModelProto, ModelProto_SentencePiece, ModelProto_SentencePiece_Type,
+    ModelProto, ModelProto_SentencePiece, ModelProto_SentencePiece_Type, NormalizerSpec,
+    TrainerSpec,
 };
 use crate::spec::TrainSpec;
+use crate::split::Boundaries;
+use crate::util;
 use anyhow::{anyhow, Result};
-use log;
+use std::cmp::Reverse;
 use std::collections::{BTreeSet, HashMap, HashSet};
-use std::fs::File;
-use std::io::BufWriter;
-use std::io::{prelude::*, BufReader};
+use std::io::prelude::*;
 
 use crate::return_err;
 
-pub fn train(spec: TrainSpec) -> Result<()> {
-    log::info!("Start train");
-    log::debug!("Config: {:?}", spec);
+pub struct Trainer {
+    spec: TrainSpec,
+}
 
-    let pieces = if cfg!(debug_assertions) && spec.slow {
-        log::warn!("Running with slow bpe");
-        slow_bpe(&spec)?
-    } else {
-        train_core(&spec)?
-    };
-    let path = spec.model_prefix.clone() + ".vocab";
-    pieces.save_pieces_tsv(&path)?;
-    log::info!("Saved vocab to {}", path);
+impl Trainer {
+    pub fn new(spec: TrainSpec) -> Self {
+        Self { spec }
+    }
 
-    let mut model = ModelProto::new();
-    model.set_pieces(pieces.to_vec().into());
-    let path = spec.model_prefix + ".model";
-    model.save(&path)?;
-    log::info!("Saved model to {}", path);
+    pub fn spec(&self) -> &TrainSpec {
+        &self.spec
+    }
 
-    Ok(())
+    pub fn train(&self) -> Result<Model> {
+        log::info!("Start train");
+        log::debug!("Config: {:?}", self.spec);
+
+        #[cfg(debug_assertions)]
+        let mut pieces = if self.spec.slow {
+            log::warn!("Running with slow bpe");
+            slow_bpe(&self.spec)?
+        } else {
+            train_core(&self.spec)?
+        };
+        #[cfg(not(debug_assertions))]
+        let mut pieces = train_core(&self.spec)?;
+
+        let mut model = ModelProto::new();
+        model.set_merges(&std::mem::take(&mut pieces.merges));
+        model.set_pieces(pieces.into_vec().into());
+        model.set_trainer_spec(self.spec.to_trainer_spec());
+        model.set_normalizer_spec(Normalizer::new(&self.spec)?.to_spec());
+        model.set_denormalizer_spec({
+            let mut spec = NormalizerSpec::new();
+            spec.set_name("identity".to_string());
+            spec
+        });
+        Model::from_proto(model)
+    }
 }
 
 #[derive(Debug)]
@@ -58,15 +79,18 @@ impl<'a> Documents<'a> {
         }
     }
 
+    /// The pair of the `left`-th and `left + 1`-th symbols from `pos`, which ends before the
+    /// `right`-th symbol.
     fn pair_words(
         &self,
         pos: (usize, usize),
         left: isize,
         right: isize,
-    ) -> Option<(&'a [char], (usize, usize))> {
+    ) -> Option<(Pair<'a>, (usize, usize))> {
         let l = self.nth_from(pos, left)?;
+        let m = self.nth_from(pos, left + 1)?;
         let r = self.nth_from(pos, right)?;
-        Some((&self.sentences[l.0][l.1..r.1], l))
+        Some(((&self.sentences[l.0][l.1..r.1], m.1 - l.1), l))
     }
 
     fn remove_node(&mut self, pos: (usize, usize)) -> Option<()> {
@@ -81,21 +105,78 @@ impl<'a> Documents<'a> {
     }
 }
 
-fn is_valid_piece(piece: &[char]) -> bool {
-    if piece.len() == 0 {
+/// Placeholder of chars out of `character_coverage` in training, as `kUNKChar` of upstream
+/// SentencePiece. Pieces containing it are never made.
+const UNK_CHAR: char = '\u{2585}';
+
+/// The char which whitespace is replaced with in the training data
+fn space_char(spec: &TrainSpec) -> char {
+    norm::space_char(spec.byte_level, spec.escape_whitespaces)
+}
+
+fn is_valid_piece(piece: &[char], spec: &TrainSpec) -> bool {
+    if piece.is_empty() || piece.len() > spec.max_sentencepiece_length {
         return false;
     }
-    if piece[piece.len() - 1] == norm::SPACE_REP {
+    let space = space_char(spec);
+    let suffix = spec.treat_whitespace_as_suffix;
+    if spec.preserve_whitespace && piece.iter().all(|&c| c == space) {
+        // runs of whitespace, e.g. indentation
+    } else if spec.split_by_whitespace {
+        // whitespace only at the beginning (or the end), so that pieces never span words
+        let inner = if suffix {
+            &piece[..piece.len() - 1]
+        } else {
+            &piece[1..]
+        };
+        if inner.contains(&space) {
+            return false;
+        }
+    } else {
+        // whitespace never at the end (or the beginning), so that it is merged with a word
+        let edge = if suffix {
+            piece[0]
+        } else {
+            piece[piece.len() - 1]
+        };
+        if edge == space {
+            return false;
+        }
+    }
+    if piece.contains(&UNK_CHAR) {
         return false;
     }
-    true
+    Boundaries::new(spec).allows(piece, space)
 }
 
+/// BPE over `sentences`, where each occurrence of a pair in `sentences[i]` counts `weights[i]`.
+///
+/// The most frequent pair is merged first. Ties are broken by the earliest occurrence of the pairs
+/// in the current segmentation of the input, i.e. the smallest `(sentence id, char offset)`. Since a
+/// position holds only one pair, no further rule is needed.
+///
+/// With `spec.word_frequency`, unique words are trained weighted by their counts instead of
+/// sentences. Words are ordered by their first occurrences in the input, so that the earliest
+/// occurrence of a pair is in the earliest word containing it. Since pieces never span words with
+/// `split_by_whitespace`, the merges are the same.
 fn train_core(spec: &TrainSpec) -> Result<Pieces> {
-    let sentences = get_sentences(&spec.input, spec)?;
-    log::info!("Loaded texts from {}", &spec.input);
+    let (sentences, weights): (Vec<_>, Vec<_>) = if spec.word_frequency {
+        if !spec.split_by_whitespace {
+            return_err!("word_frequency requires split_by_whitespace");
+        }
+        if spec.preserve_whitespace {
+            return_err!("word_frequency cannot be used with preserve_whitespace");
+        }
+        let words = get_words(spec)?;
+        log::info!("Counted {} unique words", words.len());
+        words.into_iter().unzip()
+    } else {
+        get_sentences(spec)?
+    };
+    log::info!("Loaded texts from {:?}", spec.inputs());
 
-    let mut pieces = Pieces::new(&sentences);
+    let char_freq = count_chars(&sentences, &weights, spec.byte_level);
+    let mut pieces = Pieces::new(char_freq, &spec.to_trainer_spec())?;
     log::info!("Created {} pieces", pieces.len());
     if spec.vocab_size < pieces.len() {
         let msg = format!("vocab_size must be larger than {}", pieces.len());
@@ -107,7 +188,7 @@ fn train_core(spec: &TrainSpec) -> Result<Pieces> {
         .iter()
         .map(|s| (0..s.len()).map(|i| (i.wrapping_sub(1), i + 1)).collect())
         .collect();
-    let (mut cand_pos, mut cand_pairs) = get_candidates(&sentences);
+    let (mut cand_pos, mut cand_pairs) = get_candidates(&sentences, &weights, spec.num_threads);
     let mut doc = Documents {
         sentences: &sentences,
         links,
@@ -132,19 +213,23 @@ fn train_core(spec: &TrainSpec) -> Result<Pieces> {
         // pop best pair
         let best_pair = loop {
             let pair = if let Some(last) = cand_pos.pop_last() {
-                last.1
+                last.2
             } else {
                 return_err!("vocab_size must be less than or equal to {}", pieces.len());
             };
-            if is_valid_piece(pair) {
+            if is_valid_piece(pair.0, spec) {
                 break pair;
             }
         };
         log::trace!("best pair {:?}", &best_pair);
-        pieces.add_piece(best_pair.into_iter().collect());
+        let (piece, split) = best_pair;
+        pieces.add_piece(
+            piece[..split].iter().collect(),
+            piece[split..].iter().collect(),
+        );
 
         // check all pairs
-        let positions = cand_pairs.remove(best_pair).unwrap();
+        let (_, positions) = cand_pairs.remove(&best_pair).unwrap();
         for pos in positions {
             if let Some(prev) = doc.nth_from(pos, -1) {
                 if processed.contains(&prev) {
@@ -156,13 +241,15 @@ fn train_core(spec: &TrainSpec) -> Result<Pieces> {
         }
 
         // remove candidate pairs
-        let mut remove = |pair, pos| {
-            if let Some(v) = cand_pairs.get_mut(pair) {
-                cand_pos.remove(&(v.len(), pair));
-                v.remove(&pos);
+        let mut remove = |pair, pos: (usize, usize)| {
+            if let Some((n, v)) = cand_pairs.get_mut(&pair) {
+                cand_pos.remove(&cand_key(pair, *n, v));
+                if v.remove(&pos) {
+                    *n -= weights[pos.0];
+                }
                 pairs_modified.push(pair);
-                if v.len() == 0 {
-                    cand_pairs.remove(pair);
+                if v.is_empty() {
+                    cand_pairs.remove(&pair);
                 }
             }
         };
@@ -183,10 +270,12 @@ fn train_core(spec: &TrainSpec) -> Result<Pieces> {
         }
 
         // Add new candidate pairs
-        let mut ret = |pair, pos| {
-            let v = cand_pairs.entry(pair).or_default();
-            cand_pos.remove(&(v.len(), pair));
-            v.insert(pos);
+        let mut ret = |pair, pos: (usize, usize)| {
+            let (n, v) = cand_pairs.entry(pair).or_default();
+            cand_pos.remove(&cand_key(pair, *n, v));
+            if v.insert(pos) {
+                *n += weights[pos.0];
+            }
             pairs_modified.push(pair);
         };
 
@@ -203,9 +292,9 @@ fn train_core(spec: &TrainSpec) -> Result<Pieces> {
 
         // re-compute freq for each pairs
         for pair in &pairs_modified {
-            if let Some(l) = cand_pairs.get(pair).map(|s| s.len()) {
-                if l > 0 {
-                    cand_pos.insert((l, pair));
+            if let Some((n, v)) = cand_pairs.get(pair) {
+                if *n > 0 {
+                    cand_pos.insert(cand_key(*pair, *n, v));
                 } else {
                     cand_pairs.remove(pair);
                 }
@@ -218,49 +307,135 @@ fn train_core(spec: &TrainSpec) -> Result<Pieces> {
     Ok(pieces)
 }
 
+/// Vocabulary in the layout of upstream SentencePiece BPE models: special pieces at their ids in
+/// `TrainerSpec` followed by byte pieces, then merged pieces in the order of merges, then chars in
+/// descending order of frequency.
 struct Pieces {
-    predefined: Vec<ModelProto_SentencePiece>,
+    /// `(id, piece)`
+    predefined: Vec<(usize, ModelProto_SentencePiece)>,
     chars: Vec<ModelProto_SentencePiece>,
     pieces: Vec<ModelProto_SentencePiece>,
+    /// `(left, right)` in the order of merges, including the ones making duplicated pieces
+    merges: Vec<(String, String)>,
+    /// the same piece can be made by different merges, e.g. "a" + "bc" and "ab" + "c"
+    seen: HashSet<String>,
 }
 
 impl Pieces {
-    fn new(sentences: &[Vec<char>]) -> Self {
-        Self {
-            predefined: Self::get_predefined_pieces(),
-            chars: Self::init_pieces(sentences),
+    /// `char_freq` is the output of `count_chars`.
+    fn new(char_freq: Vec<(char, usize)>, spec: &TrainerSpec) -> Result<Self> {
+        let predefined = Self::get_predefined_pieces(spec)?;
+        let chars = Self::init_pieces(char_freq);
+        let seen = predefined
+            .iter()
+            .map(|p| &p.1)
+            .chain(chars.iter())
+            .map(|p| p.get_piece().to_string())
+            .collect();
+        Ok(Self {
+            predefined,
+            chars,
             pieces: vec![],
-        }
+            merges: vec![],
+            seen,
+        })
     }
     fn len(&self) -> usize {
         self.predefined.len() + self.chars.len() + self.pieces.len()
     }
 
-    fn get_predefined_pieces() -> Vec<ModelProto_SentencePiece> {
-        let mut ret = vec![];
-        for (s, t) in &[
-            ("<unk>", ModelProto_SentencePiece_Type::UNKNOWN),
-            ("<s>", ModelProto_SentencePiece_Type::CONTROL),
-            ("</s>", ModelProto_SentencePiece_Type::CONTROL),
+    /// Special pieces at their ids, then control symbols, user defined symbols and byte pieces at
+    /// the first free ids.
+    fn get_predefined_pieces(spec: &TrainerSpec) -> Result<Vec<(usize, ModelProto_SentencePiece)>> {
+        let mut ret: Vec<(usize, ModelProto_SentencePiece)> = vec![];
+        for &(id, s, t) in &[
+            (
+                spec.get_unk_id(),
+                spec.get_unk_piece(),
+                ModelProto_SentencePiece_Type::UNKNOWN,
+            ),
+            (
+                spec.get_bos_id(),
+                spec.get_bos_piece(),
+                ModelProto_SentencePiece_Type::CONTROL,
+            ),
+            (
+                spec.get_eos_id(),
+                spec.get_eos_piece(),
+                ModelProto_SentencePiece_Type::CONTROL,
+            ),
+            (
+                spec.get_pad_id(),
+                spec.get_pad_piece(),
+                ModelProto_SentencePiece_Type::CONTROL,
+            ),
         ] {
+            if id < 0 {
+                if t == ModelProto_SentencePiece_Type::UNKNOWN {
+                    return_err!("unk_id must not be disabled");
+                }
+                continue;
+            }
+            if id >= spec.get_vocab_size() {
+                return_err!("id {} of {:?} is out of vocab_size", id, s);
+            }
+            if let Some(p) = ret.iter().find(|p| p.0 == id as usize) {
+                return_err!(
+                    "id {} is used by both {:?} and {:?}",
+                    id,
+                    p.1.get_piece(),
+                    s
+                );
+            }
+            if s.is_empty() {
+                return_err!("empty piece of id {}", id);
+            }
+            if ret.iter().any(|p| p.1.get_piece() == s) {
+                return_err!("{:?} is defined more than once", s);
+            }
             let mut p = ModelProto_SentencePiece::new();
             p.set_piece(s.to_string());
             p.set_score(0.0);
-            p.set_field_type(*t);
-            ret.push(p);
+            p.set_field_type(t);
+            ret.push((id as usize, p));
         }
-        ret
-    }
-
-    fn init_pieces(sentences: &[Vec<char>]) -> Vec<ModelProto_SentencePiece> {
-        let mut ret = HashSet::new();
-        for line in sentences {
-            for c in line {
-                ret.insert(c);
+        // the first ids not taken by the special pieces
+        let taken: HashSet<_> = ret.iter().map(|p| p.0).collect();
+        let mut ids = (0..).filter(|id| !taken.contains(id));
+        let symbols = spec
+            .get_control_symbols()
+            .iter()
+            .map(|s| (s.clone(), ModelProto_SentencePiece_Type::CONTROL))
+            .chain(
+                spec.get_user_defined_symbols()
+                    .iter()
+                    .map(|s| (s.clone(), ModelProto_SentencePiece_Type::USER_DEFINED)),
+            );
+        let bytes = (0..=255)
+            .filter(|_| spec.get_byte_fallback())
+            .map(|b| (norm::byte_piece(b), ModelProto_SentencePiece_Type::BYTE));
+        for (s, t) in symbols.chain(bytes) {
+            if s.is_empty() {
+                return_err!("empty symbol");
+            }
+            if ret.iter().any(|p| p.1.get_piece() == s) {
+                return_err!("{:?} is defined more than once", s);
             }
+            let mut p = ModelProto_SentencePiece::new();
+            p.set_piece(s);
+            p.set_score(0.0);
+            p.set_field_type(t);
+            // `ids` is infinite
+            ret.push((ids.next().unwrap(), p));
         }
-        ret.into_iter()
-            .map(|k| {
+        Ok(ret)
+    }
+
+    fn init_pieces(char_freq: Vec<(char, usize)>) -> Vec<ModelProto_SentencePiece> {
+        char_freq
+            .into_iter()
+            .filter(|&(c, _)| c != UNK_CHAR)
+            .map(|(k, _)| {
                 let mut p = ModelProto_SentencePiece::new();
                 p.set_piece(k.to_string());
                 p.set_field_type(ModelProto_SentencePiece_Type::NORMAL);
@@ -269,7 +444,13 @@ impl Pieces {
             .collect()
     }
 
-    fn add_piece(&mut self, piece: String) {
+    fn add_piece(&mut self, left: String, right: String) {
+        let piece = format!("{}{}", left, right);
+        self.merges.push((left, right));
+        if !self.seen.insert(piece.clone()) {
+            log::debug!("Skip duplicated piece {:?}", piece);
+            return;
+        }
         // Note: sort by reverse order
         let p = {
             let mut p = ModelProto_SentencePiece::new();
@@ -280,104 +461,337 @@ impl Pieces {
         self.pieces.push(p);
     }
 
-    fn iter(&self) -> impl Iterator<Item = &ModelProto_SentencePiece> {
-        self.predefined
-            .iter()
-            .chain(self.pieces.iter())
-            .chain(self.chars.iter())
-    }
-
-    fn to_vec(self) -> Vec<ModelProto_SentencePiece> {
+    fn into_vec(self) -> Vec<ModelProto_SentencePiece> {
         let Self {
-            mut predefined,
-            mut pieces,
-            mut chars,
+            predefined,
+            pieces,
+            chars,
+            ..
         } = self;
-        predefined.append(&mut pieces);
-        predefined.append(&mut chars);
-        predefined
-    }
-
-    fn save_pieces_tsv<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
-        let mut f = BufWriter::new(File::create(path)?);
-        for p in self.iter() {
-            writeln!(f, "{}\t{}", p.get_piece(), p.get_score())?;
+        let n = pieces.len();
+        let mut normal = pieces
+            .into_iter()
+            .chain(chars.into_iter().enumerate().map(|(i, mut p)| {
+                p.set_score(-((n + i) as f32));
+                p
+            }));
+        let mut predefined: HashMap<_, _> = predefined.into_iter().collect();
+        let mut ret = vec![];
+        while let Some(p) = predefined.remove(&ret.len()).or_else(|| normal.next()) {
+            ret.push(p);
         }
-        Ok(())
+        debug_assert!(predefined.is_empty());
+        ret
     }
 }
 
+/// Chars of adjacent symbols, and the length of the left one
+type Pair<'a> = (&'a [char], usize);
+
+/// Order of candidate pairs. The last one is the best.
+type CandKey<'a> = (usize, Reverse<(usize, usize)>, Pair<'a>);
+
+/// Weighted count and positions of each pair
+type PairPositions<'a> = HashMap<Pair<'a>, (usize, BTreeSet<(usize, usize)>)>;
+
+/// `(weighted count, Reverse(first position), pair)`
+fn cand_key<'a>(pair: Pair<'a>, n: usize, positions: &BTreeSet<(usize, usize)>) -> CandKey<'a> {
+    let first = positions.iter().next().copied().unwrap_or_default();
+    (n, Reverse(first), pair)
+}
+
+/// Returns the keys of all pairs, and the count and positions of each pair.
+///
+/// Sentences are split into chunks counted in parallel, and merged in order.
 fn get_candidates<'a>(
     sentences: &'a [Vec<char>],
-) -> (
-    BTreeSet<(usize, &[char])>,
-    HashMap<&[char], BTreeSet<(usize, usize)>>,
-) {
-    let mut candidates = HashMap::<_, BTreeSet<_>>::new();
-    for (i, line) in sentences.iter().enumerate() {
-        for j in 0..(line.len() - 1) {
-            let key = &line[j..j + 2];
-            candidates.entry(key).or_default().insert((i, j));
-        }
-    }
-    let mut positions = BTreeSet::new();
-    let mut pairs = HashMap::new();
-    for (key, pos) in candidates {
-        positions.insert((pos.len(), key));
-        pairs.insert(key, pos);
+    weights: &[usize],
+    num_threads: usize,
+) -> (BTreeSet<CandKey<'a>>, PairPositions<'a>) {
+    let chunks = util::map_chunks(sentences, num_threads, |offset, chunk| {
+        let mut pairs = HashMap::<_, (usize, BTreeSet<_>)>::new();
+        for (i, line) in chunk.iter().enumerate() {
+            let i = offset + i;
+            for j in 0..(line.len() - 1) {
+                let (n, pos) = pairs.entry((&line[j..j + 2], 1)).or_default();
+                *n += weights[i];
+                pos.insert((i, j));
+            }
+        }
+        pairs
+    });
+    let mut chunks = chunks.into_iter();
+    let mut pairs = chunks.next().unwrap_or_default();
+    for chunk in chunks {
+        for (key, (n, mut pos)) in chunk {
+            let (total, all) = pairs.entry(key).or_default();
+            *total += n;
+            all.append(&mut pos);
+        }
     }
+    let positions = pairs
+        .iter()
+        .map(|(&key, (n, pos))| cand_key(key, *n, pos))
+        .collect();
     (positions, pairs)
 }
 
-fn get_sentences(path: &str, spec: &TrainSpec) -> Result<Vec<Vec<char>>> {
-    let f = File::open(path)?;
-    let mut ret = vec![];
-    for line in BufReader::new(f).lines() {
-        let line = norm::to_chars(&line?, &spec);
-        if line.len() > 0 {
-            ret.push(line);
+/// `(char, frequency)` sorted by descending frequency, then by code point.
+fn count_chars(sentences: &[Vec<char>], weights: &[usize], byte_level: bool) -> Vec<(char, usize)> {
+    let mut freq = HashMap::<_, usize>::new();
+    for (line, &w) in sentences.iter().zip(weights) {
+        for c in line {
+            *freq.entry(*c).or_default() += w;
+        }
+    }
+    // every byte is in the alphabet, even if it does not appear in the input
+    if byte_level {
+        for b in 0..=255 {
+            freq.entry(norm::byte_to_char(b)).or_default();
+        }
+    }
+    let mut freq: Vec<_> = freq.into_iter().collect();
+    freq.sort_by_key(|&(c, n)| (Reverse(n), c));
+    freq
+}
+
+/// Keeps the most frequent chars covering `coverage` of the corpus, and replaces the others with
+/// `UNK_CHAR`.
+fn apply_character_coverage(
+    sentences: &mut [Vec<char>],
+    weights: &[usize],
+    spec: &TrainSpec,
+) -> Result<()> {
+    let coverage = spec.character_coverage;
+    if !(coverage > 0.0 && coverage <= 1.0) {
+        return_err!("character_coverage must be in (0, 1], but {}", coverage);
+    }
+    if spec.byte_level && coverage < 1.0 {
+        return_err!(
+            "byte_level requires character_coverage of 1, but {}",
+            coverage
+        );
+    }
+    let freq = count_chars(sentences, weights, false);
+    let total: usize = freq.iter().map(|x| x.1).sum();
+    let mut covered = 0;
+    let mut required = HashSet::new();
+    for (c, n) in freq {
+        if covered as f64 / total as f64 >= coverage as f64 {
+            break;
+        }
+        covered += n;
+        required.insert(c);
+    }
+    log::info!(
+        "{} chars cover {:.4}% of the corpus",
+        required.len(),
+        100.0 * covered as f64 / total as f64
+    );
+    for line in sentences {
+        for c in line.iter_mut() {
+            if !required.contains(c) {
+                *c = UNK_CHAR;
+            }
         }
     }
-    Ok(ret)
+    Ok(())
+}
+
+/// Lines normalized at once by each thread
+const LINES_PER_THREAD: usize = 10000;
+
+/// Passes normalized sentences of the input to `f`, sampled with `input_sentence_size`.
+///
+/// Sentences are streamed without being kept in memory if `input_sentence_size` is 0. Lines are
+/// normalized in parallel in batches, and passed in the order of the input with their counts,
+/// which are 1 except in `tsv`. Sentences with the count 0 are skipped.
+///
+/// User defined symbols are cut out of sentences, so that no piece overlaps them. With
+/// `preserve_whitespace`, each `text` file is a sentence.
+fn read_sentences(spec: &TrainSpec, mut f: impl FnMut(Vec<char>, usize)) -> Result<()> {
+    let normalizer = Normalizer::new(spec)?;
+    let symbols: Vec<_> = spec
+        .to_trainer_spec()
+        .get_user_defined_symbols()
+        .iter()
+        .map(|s| norm::symbol_chars(s, spec.byte_level))
+        .collect();
+    let mut emit = |(line, count): (Vec<char>, usize)| {
+        if symbols.is_empty() {
+            return f(line, count);
+        }
+        for (segment, symbol) in norm::split_symbols(&line, &symbols) {
+            if symbol.is_none() {
+                f(segment.to_vec(), count);
+            }
+        }
+    };
+    let mut sampler = input::Sampler::new(
+        spec.input_sentence_size,
+        spec.shuffle_input_sentence,
+        spec.random_seed,
+    );
+    let batch_size = LINES_PER_THREAD * spec.num_threads.max(1);
+    let mut lines = Vec::with_capacity(batch_size);
+    // returns false if no more sentences are needed
+    let mut flush = |lines: &mut Vec<(String, usize)>| {
+        let normalized = util::map_chunks(lines, spec.num_threads, |_, chunk| {
+            chunk
+                .iter()
+                .map(|(line, count)| (normalizer.to_chars(line), *count))
+                .collect::<Vec<_>>()
+        });
+        lines.clear();
+        for line in normalized.into_iter().flatten() {
+            if line.0.is_empty() || line.1 == 0 {
+                continue;
+            }
+            if spec.input_sentence_size == 0 {
+                emit(line);
+            } else if !sampler.add(line) {
+                return false;
+            }
+        }
+        true
+    };
+    'files: for path in input::expand_inputs(&spec.inputs())? {
+        log::debug!("Reading {:?}", path);
+        let mut reader = input::open(&path)?;
+        if spec.preserve_whitespace && spec.input_format == InputFormat::Text {
+            let mut document = String::new();
+            reader.read_to_string(&mut document)?;
+            lines.push((document, 1));
+            if lines.len() == batch_size && !flush(&mut lines) {
+                break 'files;
+            }
+            continue;
+        }
+        for (i, line) in reader.lines().enumerate() {
+            let record = spec
+                .input_format
+                .parse_line(line?, &spec.jsonl_field)
+                .map_err(|e| anyhow!("{:?}:{}: {}", path, i + 1, e))?;
+            lines.push(record);
+            if lines.len() == batch_size && !flush(&mut lines) {
+                break 'files;
+            }
+        }
+    }
+    flush(&mut lines);
+    let total = sampler.total();
+    let sampled = sampler.into_sampled();
+    if sampled.len() < total {
+        log::info!("Sampled {} sentences from {}", sampled.len(), total);
+    }
+    sampled.into_iter().for_each(emit);
+    Ok(())
+}
+
+/// Sentences of the input and their counts
+fn get_sentences(spec: &TrainSpec) -> Result<(Vec<Vec<char>>, Vec<usize>)> {
+    let mut sentences = vec![];
+    let mut weights = vec![];
+    read_sentences(spec, |s, count| {
+        sentences.push(s);
+        weights.push(count);
+    })?;
+    apply_character_coverage(&mut sentences, &weights, spec)?;
+    Ok((sentences, weights))
+}
+
+/// Splits a sentence before each `space`, or after it if `suffix`.
+fn split_words(sentence: &[char], space: char, suffix: bool) -> impl Iterator<Item = &[char]> {
+    let mut rest = sentence;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = if suffix {
+            rest.iter()
+                .position(|&c| c == space)
+                .map_or(rest.len(), |i| i + 1)
+        } else {
+            rest[1..]
+                .iter()
+                .position(|&c| c == space)
+                .map_or(rest.len(), |i| i + 1)
+        };
+        let (word, next) = rest.split_at(end);
+        rest = next;
+        Some(word)
+    })
+}
+
+/// Unique words of the input with their counts, in the order of their first occurrences.
+fn get_words(spec: &TrainSpec) -> Result<Vec<(Vec<char>, usize)>> {
+    // word -> (count, index of the first occurrence)
+    let mut counts = HashMap::<Vec<char>, (usize, usize)>::new();
+    let mut index = 0;
+    let space = space_char(spec);
+    read_sentences(spec, |s, count| {
+        for word in split_words(&s, space, spec.treat_whitespace_as_suffix) {
+            counts.entry(word.to_vec()).or_insert((0, index)).0 += count;
+            index += 1;
+        }
+    })?;
+    let (mut words, counts): (Vec<_>, Vec<_>) = counts.into_iter().unzip();
+    let weights: Vec<_> = counts.iter().map(|x| x.0).collect();
+    apply_character_coverage(&mut words, &weights, spec)?;
+    // words differing only in rare chars are the same now
+    let mut merged = HashMap::<Vec<char>, (usize, usize)>::new();
+    for (word, (n, first)) in words.into_iter().zip(counts) {
+        let entry = merged.entry(word).or_insert((0, first));
+        entry.0 += n;
+        entry.1 = entry.1.min(first);
+    }
+    let mut ret: Vec<_> = merged.into_iter().collect();
+    ret.sort_by_key(|(_, (_, first))| *first);
+    Ok(ret.into_iter().map(|(word, (n, _))| (word, n)).collect())
 }
 
 #[cfg(debug_assertions)]
 fn slow_bpe(spec: &TrainSpec) -> Result<Pieces> {
-    let sentences = get_sentences(&spec.input, spec)?;
-    let mut pieces = Pieces::new(&sentences);
+    let (sentences, weights) = get_sentences(spec)?;
+    let mut pieces = Pieces::new(
+        count_chars(&sentences, &weights, spec.byte_level),
+        &spec.to_trainer_spec(),
+    )?;
     let mut encoded: Vec<Vec<String>> = sentences
         .iter()
-        .map(|line| line.into_iter().map(|c| c.to_string()).collect())
+        .map(|line| line.iter().map(|c| c.to_string()).collect())
         .collect();
     loop {
-        // get freq for all pairs
-        let mut freq = {
-            let mut freq = HashMap::<_, usize>::new();
-            for line in &encoded {
-                for (a, b) in line.iter().zip(line.iter().skip(1)) {
-                    *freq.entry((a, b)).or_default() += 1;
+        // get freq and the first position for all pairs
+        let freq = {
+            let mut freq = HashMap::<_, (usize, (usize, usize))>::new();
+            for (i, line) in encoded.iter().enumerate() {
+                for (j, (a, b)) in line.iter().zip(line.iter().skip(1)).enumerate() {
+                    freq.entry((a, b)).or_insert((0, (i, j))).0 += weights[i];
                 }
             }
             let mut freq: Vec<_> = freq.into_iter().collect();
-            freq.sort_by_key(|x| x.1);
+            freq.sort_by_key(|&(_, (n, first))| (n, Reverse(first)));
             freq
         };
 
-        let pair = 'outer: loop {
-            while let Some(((a, b), _)) = freq.pop() {
-                if !b.ends_with(norm::SPACE_REP) {
-                    break 'outer (a.clone(), b.clone());
-                }
+        // the most frequent valid pair
+        let is_valid = |(a, b): &(&String, &String)| {
+            is_valid_piece(&a.chars().chain(b.chars()).collect::<Vec<_>>(), spec)
+        };
+        let pair = freq.into_iter().rev().map(|(pair, _)| pair).find(is_valid);
+        let (a, b) = match pair {
+            Some((a, b)) => (a.clone(), b.clone()),
+            None => {
+                return_err!(
+                    "max_size {:?}, but vocab_size {:?}",
+                    pieces.len(),
+                    spec.vocab_size
+                );
             }
-            return_err!(
-                "max_size {:?}, but vocab_size {:?}",
-                pieces.len(),
-                spec.vocab_size
-            );
         };
-        let (a, b) = pair;
         let p = format!("{}{}", a, b);
-        pieces.add_piece(p.clone());
+        pieces.add_piece(a.clone(), b.clone());
 
         encoded = encoded
             .into_iter()
@@ -404,9 +818,31 @@ fn slow_bpe(spec: &TrainSpec) -> Result<Pieces> {
     Ok(pieces)
 }
 
+/// Trains on `tests/sample1.txt` with `vocab_size`, and the other options changed by `f`.
+#[cfg(test)]
+pub(crate) fn train_sample1(vocab_size: usize, f: impl FnOnce(&mut TrainSpec)) -> Result<Model> {
+    let mut spec = TrainSpec::default();
+    spec.input = vec!["tests/sample1.txt".into()];
+    spec.vocab_size = vocab_size;
+    f(&mut spec);
+    Trainer::new(spec).train()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::unescape_piece;
+    use crate::protos::sentencepiece_model::TrainerSpec_ModelType;
+    use protobuf::Message;
+    use tempfile::TempDir;
+
+    /// Writes `contents` to a file `name` in `dir`, and returns the path.
+    fn write_file(dir: &TempDir, name: &str, contents: impl AsRef<[u8]>) -> String {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
     #[test]
     fn run_samples() {
         for (fname, vocab_size) in &[
@@ -416,11 +852,10 @@ mod tests {
             ("tests/sample4.txt", 9),
         ] {
             let mut spec = TrainSpec::default();
-            spec.input = fname.to_string();
+            spec.input = vec![fname.to_string()];
             spec.vocab_size = *vocab_size;
             spec.model_prefix = "/tmp/foo".into();
-            train(spec).unwrap();
-            println!("ok {:?}", fname); // DEBUG
+            Trainer::new(spec).train().unwrap();
         }
     }
 
@@ -432,24 +867,627 @@ mod tests {
             ("tests/sample2.txt", 6),
         ] {
             let mut spec = TrainSpec::default();
-            spec.input = fname.to_string();
+            spec.input = vec![fname.to_string()];
             spec.vocab_size = *vocab_size;
             spec.model_prefix = "/tmp/main".into();
             spec.slow = false;
-            let a: BTreeSet<_> = train_core(&spec)
+            let a: Vec<_> = train_core(&spec)
                 .unwrap()
                 .pieces
                 .into_iter()
                 .map(|x| x.get_piece().to_string())
                 .collect();
-            let b: BTreeSet<_> = slow_bpe(&spec)
+            let b: Vec<_> = slow_bpe(&spec)
                 .unwrap()
                 .pieces
                 .into_iter()
                 .map(|x| x.get_piece().to_string())
                 .collect();
             assert_eq!(a, b, "failed with {}", fname,);
-            println!("OK {}", fname);
         }
     }
+
+    #[test]
+    fn character_coverage() {
+        let mut all_chars = TrainSpec::default();
+        all_chars.input = vec!["tests/sample1.txt".into()];
+        all_chars.vocab_size = 100;
+        let (sentences, weights) = get_sentences(&all_chars).unwrap();
+        let rare = count_chars(&sentences, &weights, false).pop().unwrap().0;
+
+        let mut spec = TrainSpec::default();
+        spec.input = all_chars.input.clone();
+        spec.vocab_size = 100;
+        spec.character_coverage = 0.99;
+        let pieces = train_core(&spec).unwrap();
+        assert_eq!(pieces.len(), 100);
+        assert!(train_core(&all_chars).unwrap().chars.len() > pieces.chars.len());
+
+        let pieces = pieces.into_vec();
+        assert!(pieces.iter().all(|p| !p.get_piece().contains(UNK_CHAR)));
+        assert!(pieces.iter().all(|p| !p.get_piece().contains(rare)));
+
+        spec.character_coverage = 0.0;
+        assert!(train_core(&spec).is_err());
+    }
+
+    #[test]
+    fn multiple_inputs() {
+        let files = [
+            "tests/sample0.txt",
+            "tests/sample2.txt",
+            "tests/sample4.txt",
+        ];
+        let concat: String = files
+            .iter()
+            .map(|f| std::fs::read_to_string(f).unwrap() + "\n")
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let mut spec = TrainSpec::default();
+        spec.input = vec![write_file(&dir, "concat.txt", concat)];
+        let expected = get_sentences(&spec).unwrap();
+
+        spec.input = vec![files[0].into(), files[1..].join(",")];
+        assert_eq!(get_sentences(&spec).unwrap(), expected);
+    }
+
+    #[test]
+    fn input_format() {
+        let text = std::fs::read_to_string("tests/sample1.txt").unwrap();
+        let train = |path: &str, input_format| {
+            let mut spec = TrainSpec::default();
+            spec.input = vec![path.into()];
+            spec.input_format = input_format;
+            spec.jsonl_field = "meta.text".into();
+            spec.vocab_size = 100;
+            train_core(&spec).unwrap().into_vec()
+        };
+        let doubled: String = text.lines().map(|l| format!("{}\n{}\n", l, l)).collect();
+        let tsv: String = text.lines().map(|l| format!("{}\t2\n", l)).collect();
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            train(&write_file(&dir, "counts.tsv", tsv), InputFormat::Tsv),
+            train(&write_file(&dir, "doubled.txt", doubled), InputFormat::Text)
+        );
+
+        let jsonl: String = text
+            .lines()
+            .map(|l| serde_json::json!({ "meta": { "text": l } }).to_string() + "\n")
+            .collect();
+        let jsonl = write_file(&dir, "sample1.jsonl", jsonl);
+        let expected = train("tests/sample1.txt", InputFormat::Text);
+        assert_eq!(train(&jsonl, InputFormat::Jsonl), expected);
+
+        let mut spec = TrainSpec::default();
+        spec.input = vec!["tests/sample1.txt".into()];
+        spec.input_format = InputFormat::Tsv;
+        assert!(get_sentences(&spec).is_err());
+    }
+
+    #[test]
+    fn input_sentence_size() {
+        let mut spec = TrainSpec::default();
+        spec.input = vec!["tests/sample1.txt".into()];
+        let all = get_sentences(&spec).unwrap().0;
+        spec.input_sentence_size = 3;
+        spec.shuffle_input_sentence = false;
+        assert_eq!(get_sentences(&spec).unwrap().0, &all[..3]);
+
+        spec.shuffle_input_sentence = true;
+        let sampled = get_sentences(&spec).unwrap().0;
+        assert_eq!(sampled.len(), 3);
+        assert!(sampled.iter().all(|s| all.contains(s)));
+        assert_eq!(get_sentences(&spec).unwrap().0, sampled);
+    }
+
+    #[test]
+    fn special_ids() {
+        let model = train_sample1(100, |spec| {
+            spec.pad_id = 0;
+            spec.unk_id = 1;
+            spec.bos_id = -1;
+            spec.eos_id = 3;
+            spec.eos_piece = "<eos>".into();
+        })
+        .unwrap();
+        use ModelProto_SentencePiece_Type::*;
+        let pieces: Vec<_> = model.proto().get_pieces()[..5]
+            .iter()
+            .map(|p| (p.get_piece(), p.get_field_type()))
+            .collect();
+        assert_eq!(pieces[0], ("<pad>", CONTROL));
+        assert_eq!(pieces[1], ("<unk>", UNKNOWN));
+        assert_eq!(pieces[2].1, NORMAL);
+        assert_eq!(pieces[3], ("<eos>", CONTROL));
+        assert_eq!(pieces[4].1, NORMAL);
+        assert_eq!(model.encode_as_ids("\u{2603}").last(), Some(&1));
+
+        assert!(train_sample1(100, |s| s.unk_id = -1).is_err());
+        assert!(train_sample1(100, |s| s.eos_id = 1).is_err());
+        assert!(train_sample1(100, |s| s.pad_id = 100).is_err());
+        assert!(train_sample1(100, |s| s.bos_piece = "<unk>".into()).is_err());
+    }
+
+    #[test]
+    fn symbols() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_file(
+            &dir,
+            "symbols.txt",
+            "hello<mask>world\n<2ja>konnichiwa <mask> world\nhello world\n",
+        );
+        let mut spec = TrainSpec::default();
+        spec.input = vec![input.clone()];
+        spec.vocab_size = 30;
+        spec.control_symbols = vec!["<sep>,<cls>".into()];
+        spec.user_defined_symbols = vec!["<mask>".into(), "<2ja>".into()];
+        let model = Trainer::new(spec).train().unwrap();
+        let pieces = model.proto().get_pieces();
+        use ModelProto_SentencePiece_Type::*;
+        let types: Vec<_> = pieces[3..7]
+            .iter()
+            .map(|p| (p.get_piece(), p.get_field_type()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("<sep>", CONTROL),
+                ("<cls>", CONTROL),
+                ("<mask>", USER_DEFINED),
+                ("<2ja>", USER_DEFINED)
+            ]
+        );
+        // user defined symbols are neither split nor merged with others
+        for p in &pieces[7..] {
+            assert!(!p.get_piece().contains(['<', '>']), "{:?}", p);
+        }
+        let text = "hello<mask>world <2ja>";
+        let encoded = model.encode(text);
+        assert!(encoded.contains(&("<mask>".to_string(), 5)));
+        assert!(encoded.contains(&("<2ja>".to_string(), 6)));
+        let ids: Vec<_> = encoded.into_iter().map(|(_, id)| id).collect();
+        assert_eq!(model.decode_ids(&ids).unwrap(), text);
+
+        let mut spec = TrainSpec::default();
+        spec.input = vec![input];
+        spec.user_defined_symbols = vec!["<s>".into()];
+        assert!(Trainer::new(spec).train().is_err());
+    }
+
+    #[test]
+    fn byte_fallback() {
+        let model = train_sample1(400, |spec| spec.byte_fallback = true).unwrap();
+        let pieces = model.proto().get_pieces();
+        assert_eq!(pieces.len(), 400);
+        for b in 0..=255 {
+            let p = &pieces[3 + b as usize];
+            assert_eq!(p.get_piece(), norm::byte_piece(b));
+            assert_eq!(p.get_field_type(), ModelProto_SentencePiece_Type::BYTE);
+        }
+        assert_eq!(
+            pieces[259].get_field_type(),
+            ModelProto_SentencePiece_Type::NORMAL
+        );
+
+        let text = "日本語 テキスト";
+        let encoded = model.encode(text);
+        assert!(encoded.iter().all(|(_, id)| *id != 0));
+        assert!(encoded.iter().any(|(p, _)| p == "<0xE6>"));
+        let ids: Vec<_> = encoded.into_iter().map(|(_, id)| id).collect();
+        assert_eq!(model.decode_ids(&ids).unwrap(), text);
+    }
+
+    #[test]
+    fn byte_level() {
+        let model = train_sample1(300, |spec| spec.byte_level = true).unwrap();
+        let pieces = &model.proto().get_pieces()[3..];
+        assert_eq!(pieces.len(), 297);
+        assert_eq!(
+            pieces
+                .iter()
+                .filter(|p| p.get_piece().chars().count() == 1)
+                .count(),
+            256
+        );
+        for p in pieces {
+            assert!(p
+                .get_piece()
+                .chars()
+                .all(|c| norm::char_to_byte(c).is_some()));
+        }
+        // chars never seen in training are encoded as bytes
+        let text = "日本語 テキスト \u{2713}";
+        assert!(model.encode_as_ids(text).iter().all(|&id| id >= 3));
+        assert_eq!(model.decode_ids(&model.encode_as_ids(text)).unwrap(), text);
+
+        assert!(train_sample1(300, |spec| {
+            spec.byte_level = true;
+            spec.character_coverage = 0.99;
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn word_frequency() {
+        for (input, vocab_size, coverage) in &[
+            ("tests/sample1.txt", 200, 1.0),
+            ("tests/sample1.txt", 150, 0.99),
+            ("tests/golden/input.txt", 60, 1.0),
+        ] {
+            let mut spec = TrainSpec::default();
+            spec.input = vec![input.to_string()];
+            spec.vocab_size = *vocab_size;
+            spec.character_coverage = *coverage;
+            let expected = train_core(&spec).unwrap().into_vec();
+            spec.word_frequency = true;
+            assert_eq!(train_core(&spec).unwrap().into_vec(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn split_by_whitespace() {
+        let mut spec = TrainSpec::default();
+        spec.input = vec!["tests/sample1.txt".into()];
+        spec.vocab_size = 200;
+        let spans_words = |spec: &TrainSpec| {
+            train_core(spec)
+                .unwrap()
+                .pieces
+                .iter()
+                .any(|p| p.get_piece().chars().skip(1).any(|c| c == norm::SPACE_REP))
+        };
+        assert!(!spans_words(&spec));
+        spec.split_by_whitespace = false;
+        assert!(spans_words(&spec));
+        spec.word_frequency = true;
+        assert!(train_core(&spec).is_err());
+    }
+
+    #[test]
+    fn split_by_unicode_script() {
+        let mut spec = TrainSpec::default();
+        spec.input = vec!["tests/sample1.txt".into()];
+        spec.vocab_size = 200;
+        let boundaries = Boundaries::new(&spec);
+        let pieces = |spec: &TrainSpec| -> Vec<Vec<char>> {
+            train_core(spec)
+                .unwrap()
+                .into_vec()
+                .iter()
+                .filter(|p| p.get_field_type() == ModelProto_SentencePiece_Type::NORMAL)
+                .map(|p| p.get_piece().chars().collect())
+                .collect()
+        };
+        let spans = |pieces: &[Vec<char>]| {
+            pieces
+                .iter()
+                .any(|p| !boundaries.allows(p, norm::SPACE_REP))
+        };
+        assert!(!spans(&pieces(&spec)));
+        spec.split_by_unicode_script = false;
+        spec.split_by_number = false;
+        assert!(spans(&pieces(&spec)));
+
+        spec.split_digits = true;
+        for p in pieces(&spec) {
+            assert!(
+                p.len() == 1 || !p.iter().any(char::is_ascii_digit),
+                "{:?}",
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn normalization_rule_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_file(&dir, "rule.txt", "Café ＣＡＦÉ\ncafé\n");
+        let spec = |rule: &str| {
+            let mut spec = TrainSpec::default();
+            spec.input = vec![input.clone()];
+            spec.vocab_size = 10;
+            spec.normalization_rule_name = rule.into();
+            spec
+        };
+        let alphabet = |spec: &TrainSpec| {
+            let mut chars: Vec<_> = get_sentences(spec).unwrap().0.concat();
+            chars.sort();
+            chars.dedup();
+            chars.into_iter().collect::<String>()
+        };
+        assert_eq!(alphabet(&spec("nfkd")), "ACEFacef\u{301}▁");
+        assert_eq!(alphabet(&spec("nfc")), "CacfÉé▁ＡＣＦ");
+        assert_eq!(alphabet(&spec("nfkc_cf")), "acfé▁");
+
+        let model = Trainer::new(spec("nfkc_cf")).train().unwrap();
+        assert_eq!(model.proto().get_normalizer_spec().get_name(), "nfkc_cf");
+        let path = dir.path().join("rule.model");
+        model.save(&path).unwrap();
+        // the rule of the model takes precedence
+        let mut spec = spec("nfc");
+        spec.normalizer_model = Some(path.to_str().unwrap().into());
+        assert_eq!(alphabet(&spec), "acfé▁");
+
+        spec.normalizer_model = None;
+        spec.normalization_rule_name = "nfkx".into();
+        assert!(get_sentences(&spec).is_err());
+    }
+
+    #[test]
+    fn normalization_rule_tsv() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_file(&dir, "rule.txt", "Café。café、\nCAFÉ、Café。\n".repeat(3));
+        // removes acute accents and maps the ideographic full stop and comma
+        let rules = write_file(&dir, "rule.tsv", "301\t\n3002\t2E\n3001\t2C\n");
+        let spec = |rules: Option<&str>| {
+            let mut spec = TrainSpec::default();
+            spec.input = vec![input.clone()];
+            spec.vocab_size = 20;
+            spec.normalization_rule_tsv = rules.map(String::from);
+            spec
+        };
+        let alphabet = |spec: &TrainSpec| {
+            let mut chars: Vec<_> = get_sentences(spec).unwrap().0.concat();
+            chars.sort();
+            chars.dedup();
+            chars.into_iter().collect::<String>()
+        };
+        assert_eq!(alphabet(&spec(None)), "ACEFacef\u{301}▁、。");
+        assert_eq!(alphabet(&spec(Some(&rules))), ",.ACEFacef▁");
+
+        let model = Trainer::new(spec(Some(&rules))).train().unwrap();
+        for p in model.proto().get_pieces() {
+            assert!(!p.get_piece().contains(['\u{301}', '。', '、']), "{:?}", p);
+        }
+        let text = "café。CAFÉ、";
+        let pieces = model.encode_as_pieces(text);
+        assert_eq!(pieces.concat(), "▁cafe.CAFE,");
+        let without_rules = Trainer::new(spec(None)).train().unwrap();
+        assert_ne!(without_rules.encode_as_pieces(text), pieces);
+
+        // the rules are saved as `precompiled_charsmap`
+        let path = dir.path().join("rule.model");
+        model.save(&path).unwrap();
+        let loaded = Model::load(&path).unwrap();
+        let normalizer_spec = loaded.proto().get_normalizer_spec();
+        assert_eq!(normalizer_spec.get_name(), norm::USER_DEFINED);
+        assert!(!normalizer_spec.get_precompiled_charsmap().is_empty());
+        assert_eq!(loaded.encode_as_pieces(text), pieces);
+
+        for (i, tsv) in ["3002 2E\n", "301\t\n3002\tZZ\n"].iter().enumerate() {
+            let rules = write_file(&dir, &format!("{}.tsv", i), tsv);
+            let err = Trainer::new(spec(Some(&rules))).train().err().unwrap();
+            assert!(err.to_string().contains("at line"), "{:?}: {}", tsv, err);
+        }
+    }
+
+    #[test]
+    fn max_sentencepiece_length() {
+        let mut spec = TrainSpec::default();
+        spec.input = vec!["tests/sample1.txt".into()];
+        spec.vocab_size = 200;
+        let max_len = |spec: &TrainSpec| {
+            train_core(spec)
+                .unwrap()
+                .pieces
+                .iter()
+                .map(|p| p.get_piece().chars().count())
+                .max()
+                .unwrap()
+        };
+        assert!(max_len(&spec) > 4);
+        spec.max_sentencepiece_length = 4;
+        assert_eq!(max_len(&spec), 4);
+
+        spec.vocab_size = 80;
+        spec.max_sentencepiece_length = 3;
+        assert_eq!(
+            train_core(&spec).unwrap().into_vec(),
+            slow_bpe(&spec).unwrap().into_vec()
+        );
+    }
+
+    #[test]
+    fn treat_whitespace_as_suffix() {
+        let mut spec = TrainSpec::default();
+        spec.input = vec!["tests/sample1.txt".into()];
+        spec.vocab_size = 200;
+        spec.treat_whitespace_as_suffix = true;
+        let pieces: Vec<_> = train_core(&spec)
+            .unwrap()
+            .into_vec()
+            .iter()
+            .map(|p| p.get_piece().to_string())
+            .collect();
+        assert!(pieces
+            .iter()
+            .any(|p| p.chars().count() > 1 && p.ends_with(norm::SPACE_REP)));
+        for p in &pieces {
+            let inner = p.trim_end_matches(norm::SPACE_REP);
+            assert!(
+                p.chars().count() == 1 || !inner.contains(norm::SPACE_REP),
+                "{:?}",
+                p
+            );
+        }
+        let expected = train_core(&spec).unwrap().into_vec();
+        spec.word_frequency = true;
+        assert_eq!(train_core(&spec).unwrap().into_vec(), expected);
+
+        spec.word_frequency = false;
+        spec.split_by_whitespace = false;
+        for p in train_core(&spec).unwrap().into_vec() {
+            let p = p.get_piece();
+            assert!(
+                p.chars().count() == 1 || !p.starts_with(norm::SPACE_REP),
+                "{:?}",
+                p
+            );
+        }
+
+        spec.split_by_whitespace = true;
+        let model = Trainer::new(spec).train().unwrap();
+        let text = "the history of the city";
+        let pieces = model.encode_as_pieces(text);
+        assert!(pieces.last().unwrap().ends_with(norm::SPACE_REP));
+        assert_eq!(model.decode_pieces(pieces.iter().map(|p| p.as_str())), text);
+    }
+
+    #[test]
+    fn preserve_whitespace() {
+        let code = "def f(x):\n    if x:\n        return x\n    return  0\n\n\tdef g(y):\n";
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_file(&dir, "code.py", code.repeat(3));
+        let mut spec = TrainSpec::default();
+        spec.input = vec![input.clone()];
+        spec.vocab_size = 40;
+        spec.preserve_whitespace = true;
+        let pieces: Vec<_> = train_core(&spec)
+            .unwrap()
+            .into_vec()
+            .iter()
+            .map(|p| p.get_piece().to_string())
+            .collect();
+        assert!(pieces.contains(&"\n".to_string()));
+        assert!(pieces.contains(&"\t".to_string()));
+        assert!(pieces.contains(&"▁▁▁▁".to_string()), "{:?}", pieces);
+
+        let model = Trainer::new(spec).train().unwrap();
+        assert!(model.normalizer().preserve_whitespace);
+        for text in &[code, "  if y:\n\treturn   0\n\n"] {
+            let ids = model.encode_as_ids(text);
+            assert_eq!(&model.decode_ids(&ids).unwrap(), text);
+        }
+
+        // pieces with line breaks and tabs are escaped, one per line
+        let vocab_path = dir.path().join("code.vocab");
+        model.save_vocab(&vocab_path).unwrap();
+        let vocab = std::fs::read_to_string(&vocab_path).unwrap();
+        let pieces: Vec<_> = vocab
+            .lines()
+            .map(|line| unescape_piece(line.split('\t').next().unwrap()).unwrap())
+            .collect();
+        let expected = model.proto().get_pieces().iter().map(|p| p.get_piece());
+        assert!(pieces.iter().eq(expected));
+        let merges_path = dir.path().join("code.merges");
+        model.save_merges(&merges_path).unwrap();
+        let merges = std::fs::read_to_string(&merges_path).unwrap();
+        let merges: Vec<_> = merges
+            .lines()
+            .map(|line| {
+                let (left, right) = line.split_once('\t').unwrap();
+                (
+                    unescape_piece(left).unwrap(),
+                    unescape_piece(right).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(merges, model.merges());
+
+        let mut spec = TrainSpec::default();
+        spec.input = vec![input];
+        spec.preserve_whitespace = true;
+        spec.word_frequency = true;
+        assert!(train_core(&spec).is_err());
+    }
+
+    #[test]
+    fn num_threads() {
+        for &word_frequency in &[false, true] {
+            let train = |num_threads| {
+                let mut spec = TrainSpec::default();
+                spec.input = vec!["tests/sample1.txt".into(), "tests/golden/input.txt".into()];
+                spec.vocab_size = 200;
+                spec.word_frequency = word_frequency;
+                spec.num_threads = num_threads;
+                let model = Trainer::new(spec).train().unwrap();
+                model.proto().write_to_bytes().unwrap()
+            };
+            let expected = train(1);
+            for &n in &[2, 16] {
+                assert!(train(n) == expected, "num_threads: {}", n);
+            }
+        }
+    }
+
+    #[test]
+    fn tie_break() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spec = TrainSpec::default();
+        spec.input = vec![write_file(&dir, "tie_break.txt", "xy ab\n")];
+        // 3 special pieces and 5 chars
+        spec.vocab_size = 12;
+        let pieces: Vec<_> = train_core(&spec)
+            .unwrap()
+            .pieces
+            .iter()
+            .map(|p| p.get_piece().to_string())
+            .collect();
+        assert_eq!(pieces, vec!["▁x", "▁xy", "▁a", "▁ab"]);
+    }
+
+    #[test]
+    fn deterministic() {
+        let dir = tempfile::tempdir().unwrap();
+        let train = |name: &str| {
+            let mut spec = TrainSpec::default();
+            spec.input = vec!["tests/sample1.txt".into(), "tests/golden/input.txt".into()];
+            spec.vocab_size = 250;
+            spec.character_coverage = 0.995;
+            let path = dir.path().join(name);
+            Trainer::new(spec).train().unwrap().save(&path).unwrap();
+            std::fs::read(path).unwrap()
+        };
+        assert!(train("deterministic1.model") == train("deterministic2.model"));
+    }
+
+    #[test]
+    fn merges() {
+        let model = train_sample1(200, |_| ()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("merges.model");
+        model.save(&path).unwrap();
+        let loaded = Model::load(&path).unwrap();
+        assert_eq!(loaded.merges(), model.merges());
+
+        let pieces: HashSet<_> = model
+            .proto()
+            .get_pieces()
+            .iter()
+            .map(|p| p.get_piece())
+            .collect();
+        // 3 special pieces and chars
+        let n_chars = model.proto().get_pieces()[3..]
+            .iter()
+            .filter(|p| p.get_piece().chars().count() == 1)
+            .count();
+        assert!(model.merges().len() >= 200 - 3 - n_chars);
+        for (i, (left, right)) in model.merges().iter().enumerate() {
+            assert!(pieces.contains(left.as_str()), "{}", left);
+            assert!(pieces.contains(right.as_str()), "{}", right);
+            assert!(pieces.contains(format!("{}{}", left, right).as_str()));
+            if i == 0 {
+                assert_eq!(
+                    model.proto().get_pieces()[3].get_piece(),
+                    left.clone() + right
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn save_and_load_specs() {
+        let model = train_sample1(100, |spec| spec.keep_extra_whitespaces = true).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("specs.model");
+        model.save(&path).unwrap();
+
+        let model = Model::load(&path).unwrap();
+        let trainer_spec = model.proto().get_trainer_spec();
+        assert_eq!(trainer_spec.get_model_type(), TrainerSpec_ModelType::BPE);
+        assert_eq!(trainer_spec.get_vocab_size(), 100);
+        assert_eq!(trainer_spec.get_input(), &["tests/sample1.txt".to_string()]);
+        assert_eq!(model.proto().get_normalizer_spec().get_name(), "nfkd");
+        assert!(model.normalizer().keep_extra_whitespaces);
+    }
 }
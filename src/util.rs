@@ -1,3 +1,5 @@
+use protobuf::UnknownFields;
+
 #[macro_export]
 macro_rules! return_err {
     ($($arg:tt)*) => {
@@ -5,3 +7,46 @@ macro_rules! return_err {
         return Err(anyhow!($($arg)*));
     };
 }
+
+/// Applies `f` to contiguous chunks of `items` on up to `num_threads` threads, and returns the
+/// results in the order of the chunks. `f` takes the offset of the chunk in `items`.
+pub fn map_chunks<'a, T, R, F>(items: &'a [T], num_threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(usize, &'a [T]) -> R + Sync,
+{
+    if num_threads <= 1 || items.len() <= 1 {
+        return vec![f(0, items)];
+    }
+    let chunk_size = items.len().div_ceil(num_threads);
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| s.spawn(move || f(i * chunk_size, chunk)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+/// Reads the bool extension `field` of a message from its unknown fields. The last value wins, as
+/// in protobuf.
+pub fn get_bool_extension(fields: &UnknownFields, field: u32) -> bool {
+    fields
+        .get(field)
+        .and_then(|values| values.varint.last())
+        .is_some_and(|&v| v != 0)
+}
+
+/// Writes the bool extension `field` of a message to its unknown fields. `false` is not written,
+/// as it is the default.
+pub fn set_bool_extension(fields: &mut UnknownFields, field: u32, v: bool) {
+    if let Some(fields) = &mut fields.fields {
+        fields.remove(&field);
+    }
+    if v {
+        fields.add_varint(field, 1);
+    }
+}
@@ -0,0 +1,419 @@
+//! Compatibility with upstream SentencePiece BPE models and GPT-2 style models.
+//!
+//! `tests/golden/*.model` are trained by this crate, and pin its output against regressions.
+//! `tests/golden/input.txt` is encoded with each of them, then compared with `<model>.pieces` and
+//! `<model>.ids`. Set `BPE_UPDATE_GOLDEN=1` to regenerate them.
+//!
+//! `tests/upstream` holds files produced by upstream SentencePiece, which are never regenerated.
+//! See `tests/upstream/README.md`.
+#![allow(clippy::field_reassign_with_default)]
+use bpe::protos::sentencepiece_model::{
+    ModelProto, ModelProto_SentencePiece, ModelProto_SentencePiece_Type, NormalizerSpec,
+    TrainerSpec_ModelType,
+};
+use bpe::{CharsMap, Model, Normalizer, Rule, TrainSpec, Trainer};
+use protobuf::Message;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const GOLDEN_DIR: &str = "tests/golden";
+const UPSTREAM_DIR: &str = "tests/upstream";
+
+fn update_golden() -> bool {
+    env::var("BPE_UPDATE_GOLDEN").is_ok()
+}
+
+fn encode_all(model: &Model, sep: &str, f: impl Fn(Vec<(String, usize)>) -> Vec<String>) -> String {
+    encode_file(model, &Path::new(GOLDEN_DIR).join("input.txt"), sep, f)
+}
+
+fn encode_file(
+    model: &Model,
+    input: &Path,
+    sep: &str,
+    f: impl Fn(Vec<(String, usize)>) -> Vec<String>,
+) -> String {
+    let input = fs::read_to_string(input).unwrap();
+    input
+        .lines()
+        .map(|line| f(model.encode(line)).join(sep) + "\n")
+        .collect()
+}
+
+fn check_golden(path: &Path, actual: &str) {
+    if update_golden() {
+        fs::write(path, actual).unwrap();
+    }
+    let expected = fs::read_to_string(path).unwrap();
+    assert_eq!(actual, expected, "{:?}", path);
+}
+
+/// Trains `sample1.model`, and saves it if `BPE_UPDATE_GOLDEN` is set.
+fn train_sample1() -> Model {
+    let mut spec = TrainSpec::default();
+    spec.input = vec!["tests/sample1.txt".into()];
+    spec.vocab_size = 200;
+    spec.model_prefix = "tests/golden/sample1".into();
+    let model = Trainer::new(spec).train().unwrap();
+    if update_golden() {
+        let path = Path::new(GOLDEN_DIR).join("sample1.model");
+        model.save(&path).unwrap();
+        model.save_vocab(path.with_extension("vocab")).unwrap();
+    }
+    model
+}
+
+#[test]
+fn train_golden() {
+    let model = train_sample1();
+    let path = Path::new(GOLDEN_DIR).join("sample1.model");
+    let bytes = model.proto().write_to_bytes().unwrap();
+    assert!(bytes == fs::read(&path).unwrap(), "{:?} differs", path);
+}
+
+#[test]
+fn encode_golden() {
+    if update_golden() {
+        train_sample1();
+    }
+    let mut n = 0;
+    for entry in fs::read_dir(GOLDEN_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "model") {
+            continue;
+        }
+        let model = Model::load(&path).unwrap();
+        let pieces = encode_all(&model, " ", |v| v.into_iter().map(|x| x.0).collect());
+        check_golden(&path.with_extension("pieces"), &pieces);
+        let ids = encode_all(&model, " ", |v| {
+            v.into_iter().map(|x| x.1.to_string()).collect()
+        });
+        check_golden(&path.with_extension("ids"), &ids);
+        n += 1;
+    }
+    assert!(n > 0);
+}
+
+/// The `precompiled_charsmap` of golden models, which upstream SentencePiece normalizes with,
+/// gives the same result as the rule of its name, including the combining marks out of the
+/// canonical order in `input.txt`.
+#[test]
+fn golden_charsmap() {
+    let input = fs::read_to_string(Path::new(GOLDEN_DIR).join("input.txt")).unwrap();
+    let mut n = 0;
+    for entry in fs::read_dir(GOLDEN_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "model") {
+            continue;
+        }
+        let mut spec = ModelProto::load(&path).unwrap().take_normalizer_spec();
+        // this crate normalizes with the named rule, upstream SentencePiece with the charsmap
+        let named = Normalizer::from_spec(&spec).unwrap();
+        assert!(!matches!(named.rule, Rule::Precompiled { .. }));
+        spec.set_generated_charsmap(false);
+        let precompiled = Normalizer::from_spec(&spec).unwrap();
+        assert!(matches!(precompiled.rule, Rule::Precompiled { .. }));
+        for line in input.lines() {
+            assert_eq!(
+                precompiled.to_chars(line),
+                named.to_chars(line),
+                "{:?}",
+                path
+            );
+        }
+        n += 1;
+    }
+    assert!(n > 0);
+}
+
+/// Models trained by `spm_train` are encoded as `spm_encode` does. Never regenerated.
+#[test]
+#[ignore = "needs files of spm_train and spm_encode, see tests/upstream/README.md"]
+fn encode_upstream() {
+    let mut n = 0;
+    for entry in fs::read_dir(UPSTREAM_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "model") {
+            continue;
+        }
+        let model = Model::load(&path).unwrap();
+        let input = path.with_extension("txt");
+        let pieces = encode_file(&model, &input, " ", |v| {
+            v.into_iter().map(|x| x.0).collect()
+        });
+        let expected = fs::read_to_string(path.with_extension("pieces")).unwrap();
+        assert_eq!(pieces, expected, "{:?}", path);
+        let ids = encode_file(&model, &input, " ", |v| {
+            v.into_iter().map(|x| x.1.to_string()).collect()
+        });
+        let expected = fs::read_to_string(path.with_extension("ids")).unwrap();
+        assert_eq!(ids, expected, "{:?}", path);
+        n += 1;
+    }
+    assert!(n > 0);
+}
+
+/// Golden models are encoded as `spm_encode` does, which normalizes with `precompiled_charsmap`
+/// and merges by scores instead of the merges of the model. Compares the encoding of
+/// `tests/golden/input.txt` with `tests/upstream/golden_<model>.pieces` and `.ids`.
+#[test]
+#[ignore = "needs files of spm_encode, see tests/upstream/README.md"]
+fn encode_golden_upstream() {
+    let mut n = 0;
+    for entry in fs::read_dir(GOLDEN_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "model") {
+            continue;
+        }
+        let model = Model::load(&path).unwrap();
+        let name = format!("golden_{}", path.file_stem().unwrap().to_str().unwrap());
+        let expected = Path::new(UPSTREAM_DIR).join(name);
+        let pieces = encode_all(&model, " ", |v| v.into_iter().map(|x| x.0).collect());
+        let spm_pieces = fs::read_to_string(expected.with_extension("pieces")).unwrap();
+        assert_eq!(pieces, spm_pieces, "{:?}", path);
+        let ids = encode_all(&model, " ", |v| {
+            v.into_iter().map(|x| x.1.to_string()).collect()
+        });
+        let spm_ids = fs::read_to_string(expected.with_extension("ids")).unwrap();
+        assert_eq!(ids, spm_ids, "{:?}", path);
+        n += 1;
+    }
+    assert!(n > 0);
+}
+
+/// `precompiled_charsmap` of `nmt_nfkc` written by upstream SentencePiece.
+fn nmt_nfkc() -> Vec<u8> {
+    fs::read(Path::new(UPSTREAM_DIR).join("nmt_nfkc.charsmap")).unwrap()
+}
+
+#[test]
+fn upstream_charsmap() {
+    let blob = nmt_nfkc();
+    let charsmap = CharsMap::from_blob(&blob).unwrap();
+    assert_eq!(charsmap.to_blob(), blob);
+    assert_eq!(charsmap.normalize_prefix("\u{fb01}x"), Some((3, "fi")));
+    assert_eq!(charsmap.normalize_prefix("𝔾"), Some((4, "G")));
+    assert_eq!(charsmap.normalize_prefix("𝕠"), Some((4, "o")));
+    assert_eq!(charsmap.normalize_prefix("\u{200d}"), Some((3, " ")));
+    assert_eq!(charsmap.normalize_prefix(" "), None);
+    assert_eq!(charsmap.normalize_prefix("a"), None);
+    // a rule of two chars
+    assert_eq!(
+        charsmap.normalize_prefix("\u{627}\u{653}"),
+        Some((4, "\u{622}"))
+    );
+
+    let normalize = |s: &str| -> String {
+        let mut ret = String::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            match charsmap.normalize_prefix(rest) {
+                Some((len, normalized)) => {
+                    ret.push_str(normalized);
+                    rest = &rest[len..];
+                }
+                None => {
+                    ret.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        ret
+    };
+    assert_eq!(
+        normalize("เขาไม่ได้พูดสักคำ"),
+        "เขาไม\u{e48}ได\u{e49}พ\u{e39}ดส\u{e31}กค\u{e4d}า"
+    );
+    assert_eq!(normalize("ड़ी दुख"), "ड\u{93c}ी द\u{941}ख");
+    assert_eq!(normalize("ＡＢＣ①"), "ABC1");
+}
+
+#[test]
+fn encode_alignment() {
+    let model = Model::load(Path::new(GOLDEN_DIR).join("sample1.model")).unwrap();
+    let input = fs::read_to_string(Path::new(GOLDEN_DIR).join("input.txt")).unwrap();
+    for line in input.lines().chain(vec!["  Ｈｅｌｌｏ,\u{3000}world!  "]) {
+        let spt = model.encode_to_proto(line);
+        let pieces: Vec<_> = spt.get_pieces().iter().map(|p| p.get_piece()).collect();
+        assert_eq!(pieces, model.encode_as_pieces(line));
+        // surfaces are contiguous and cover the whole line
+        let mut end = 0;
+        for p in spt.get_pieces() {
+            assert_eq!(p.get_begin(), end, "{:?}", line);
+            assert_eq!(
+                p.get_surface(),
+                &line[p.get_begin() as usize..p.get_end() as usize]
+            );
+            end = p.get_end();
+        }
+        if !pieces.is_empty() {
+            assert_eq!(end as usize, line.len());
+        }
+    }
+}
+
+/// A model in the layout of `spm_train --model_type=bpe --pad_id=0 --unk_id=1 --bos_id=2
+/// --eos_id=-1`: special pieces at their ids, merged pieces with scores 0, -1, ..., then chars,
+/// and a `precompiled_charsmap`.
+fn upstream_layout_model() -> ModelProto {
+    use ModelProto_SentencePiece_Type::*;
+    let mut model = ModelProto::new();
+    let pieces = &[
+        ("<pad>", CONTROL),
+        ("<unk>", UNKNOWN),
+        ("<s>", CONTROL),
+        ("▁a", NORMAL),
+        ("bc", NORMAL),
+        ("▁abc", NORMAL),
+        ("▁", NORMAL),
+        ("a", NORMAL),
+        ("b", NORMAL),
+        ("c", NORMAL),
+    ];
+    for (i, &(s, t)) in pieces.iter().enumerate() {
+        let mut p = ModelProto_SentencePiece::new();
+        p.set_piece(s.to_string());
+        if t == NORMAL {
+            p.set_score(-((i - 3) as f32));
+        } else {
+            p.set_field_type(t);
+        }
+        model.mut_pieces().push(p);
+    }
+
+    let spec = model.mut_trainer_spec();
+    spec.set_model_type(TrainerSpec_ModelType::BPE);
+    spec.set_vocab_size(pieces.len() as i32);
+    spec.set_pad_id(0);
+    spec.set_unk_id(1);
+    spec.set_bos_id(2);
+    spec.set_eos_id(-1);
+
+    let mut spec = NormalizerSpec::new();
+    spec.set_name("nmt_nfkc".into());
+    spec.set_precompiled_charsmap(nmt_nfkc());
+    model.set_normalizer_spec(spec);
+    model
+}
+
+#[test]
+fn load_upstream_layout() {
+    let model = Model::from_proto(upstream_layout_model()).unwrap();
+    assert_eq!(
+        model.encode_as_pieces("ａbc\tabc  cx"),
+        vec!["▁abc", "▁abc", "▁", "c", "x"]
+    );
+    assert_eq!(model.encode_as_ids("ａbc\tabc  cx"), vec![5, 5, 6, 9, 1]);
+    assert_eq!(model.decode_ids(&[2, 5, 5, 6, 9, 1]).unwrap(), "abc abc c \u{2047} ");
+}
+
+#[test]
+fn trained_layout() {
+    let mut spec = TrainSpec::default();
+    spec.input = vec!["tests/sample1.txt".into()];
+    spec.vocab_size = 100;
+    let model = Trainer::new(spec).train().unwrap();
+    let pieces = model.proto().get_pieces();
+    assert_eq!(pieces[0].get_field_type(), ModelProto_SentencePiece_Type::UNKNOWN);
+    assert_eq!(pieces[1].get_piece(), "<s>");
+    assert_eq!(pieces[2].get_piece(), "</s>");
+    // scores are strictly decreasing after the special pieces
+    for (a, b) in pieces[3..].iter().zip(pieces[4..].iter()) {
+        assert!(a.get_score() > b.get_score(), "{:?} {:?}", a, b);
+    }
+    // the most frequent char comes first among chars
+    let chars: Vec<_> = pieces[3..]
+        .iter()
+        .filter(|p| p.get_piece().chars().count() == 1)
+        .collect();
+    assert_eq!(chars[0].get_piece(), "\u{2581}");
+    assert!(!model.proto().get_normalizer_spec().get_precompiled_charsmap().is_empty());
+}
+
+#[test]
+fn gpt2_round_trip() {
+    let dir = Path::new("tests/gpt2");
+    let model = Model::load_gpt2(dir.join("vocab.json"), dir.join("merges.txt")).unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("gpt2.model");
+    model.save(&path).unwrap();
+    let model = Model::load(&path).unwrap();
+
+    let pieces = model.proto().get_pieces();
+    assert_eq!(pieces[22].get_piece(), "Ġhello");
+    assert_eq!(
+        pieces[23].get_field_type(),
+        ModelProto_SentencePiece_Type::USER_DEFINED
+    );
+    assert_eq!(
+        pieces[24].get_field_type(),
+        ModelProto_SentencePiece_Type::UNKNOWN
+    );
+    // GPT-2 adds no dummy prefix, and matches special tokens in text
+    assert_eq!(
+        model.encode("hello, world!<|endoftext|>"),
+        vec![
+            ("hello".to_string(), 19),
+            (",".to_string(), 1),
+            ("Ġworld".to_string(), 21),
+            ("!".to_string(), 0),
+            ("<|endoftext|>".to_string(), 23),
+        ]
+    );
+    assert_eq!(
+        model.decode_ids(&[22, 1, 21, 0, 23]).unwrap(),
+        " hello, world!<|endoftext|>"
+    );
+    assert!(model.proto().get_trainer_spec().get_split_by_gpt2_pattern());
+
+    let (vocab, merges) = (tmp.path().join("vocab.json"), tmp.path().join("merges.txt"));
+    model.save_gpt2(&vocab, &merges).unwrap();
+    let json = |path: &Path| -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    };
+    assert_eq!(json(&vocab), json(&dir.join("vocab.json")));
+    assert_eq!(
+        fs::read_to_string(&merges).unwrap(),
+        fs::read_to_string(dir.join("merges.txt")).unwrap()
+    );
+}
+
+#[test]
+fn cli_round_trip() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = |name: &str| tmp.path().join(name).to_str().unwrap().to_string();
+    let bpe = |args: &[&str], model: &str, out: &str| {
+        let status = Command::new(env!("CARGO_BIN_EXE_bpe"))
+            .args(args)
+            .args(["-m", model, "-o", out])
+            .status()
+            .unwrap();
+        assert!(status.success(), "{:?}", args);
+    };
+    let code = "def f(x):\n    if x:\n\treturn  x \\ 1\r\n\n";
+    let text = "hello world\nthe history of the city\n";
+    for (name, input, preserve_whitespace) in &[("code", code, true), ("text", text, false)] {
+        let input_path = path(&format!("{}.txt", name));
+        fs::write(&input_path, input.repeat(3)).unwrap();
+        let mut spec = TrainSpec::default();
+        spec.input = vec![input_path.clone()];
+        spec.vocab_size = 40;
+        spec.preserve_whitespace = *preserve_whitespace;
+        spec.escape_whitespaces = *preserve_whitespace;
+        let model = path(&format!("{}.model", name));
+        Trainer::new(spec).train().unwrap().save(&model).unwrap();
+
+        for format in &["piece", "id"] {
+            let (encoded, decoded) = (path("encoded.txt"), path("decoded.txt"));
+            let format_opt = &format!("--output-format={}", format);
+            bpe(&["encode", &input_path, format_opt], &model, &encoded);
+            let lines = fs::read_to_string(&encoded).unwrap().lines().count();
+            assert_eq!(lines, if *preserve_whitespace { 1 } else { 6 });
+            let format_opt = &format!("--input-format={}", format);
+            bpe(&["decode", &encoded, format_opt], &model, &decoded);
+            assert_eq!(fs::read_to_string(&decoded).unwrap(), input.repeat(3));
+        }
+    }
+}